@@ -2,7 +2,7 @@ extern crate proc_macro;
 
 use proc_macro::TokenStream;
 use proc_macro2::Span;
-use syn::{parse_macro_input,  Fields, ItemStruct, Ident, FieldsNamed, ExprLit, Lit, LitInt};
+use syn::{parse_macro_input,  Fields, ItemStruct, Ident, FieldsNamed, ExprLit, Lit, LitInt, DeriveInput, Data};
 //use syn::parse::{Parse, ParseStream};
 use quote::quote;
 
@@ -21,6 +21,40 @@ use quote::quote;
 //}
 //
 
+/// Implement `FieldValue` for enum without fields, bytes read from flash
+/// are accepted only if they are bytes of one of variants
+#[proc_macro_derive(FieldValue)]
+pub fn derive_field_value(input: TokenStream) -> TokenStream {
+    let i = parse_macro_input!(input as DeriveInput);
+    let ty_name = &i.ident;
+
+    let variants = match &i.data {
+        Data::Enum(data) if i.generics.params.is_empty() && data.variants.iter().all(|v| v.fields.is_empty()) => &data.variants,
+        _ => {
+            return syn::Error::new(ty_name.span(), "FieldValue can be derived only for enum without fields and generics")
+                .to_compile_error()
+                .into();
+        }
+    };
+    let variant_name : Vec<_> = variants.iter().map(|v| &v.ident).collect();
+
+    let out = quote!(
+        // Enum without fields has no padding, it is its discriminant only
+        unsafe impl ::iced::FieldValue for #ty_name {
+            fn from_bytes(bytes : &[u8]) -> Option<Self> {
+                ::core::iter::IntoIterator::into_iter([ #( #ty_name::#variant_name ),* ]).find(|variant| {
+                    let variant_bytes = unsafe {
+                        ::core::slice::from_raw_parts(variant as *const Self as *const u8, ::core::mem::size_of::<Self>())
+                    };
+                    variant_bytes == bytes
+                })
+            }
+        }
+    );
+
+    out.into()
+}
+
 #[proc_macro]
 pub fn generate_storage_ty(input: TokenStream) -> TokenStream {
     let i = parse_macro_input!(input as ItemStruct);
@@ -31,6 +65,9 @@ pub fn generate_storage_ty(input: TokenStream) -> TokenStream {
 
     let ty_name = Ident::new(&ty_name_str, Span::call_site());
     let _un_ty_name = Ident::new(&format!("Recast{}", &ty_name_str), Span::call_site());
+    let async_ty_name = Ident::new(&format!("Async{}", &ty_name_str), Span::call_site());
+    let tx_ty_name = Ident::new(&format!("{}Tx", &ty_name_str), Span::call_site());
+    let cached_ty_name = Ident::new(&format!("Cached{}", &ty_name_str), Span::call_site());
 
     let fields = if let ItemStruct { fields : Fields::Named( FieldsNamed{ named, .. } ), .. } = &i {
         named
@@ -47,19 +84,23 @@ pub fn generate_storage_ty(input: TokenStream) -> TokenStream {
         &f.ty
     }).collect();
 
-    let setter_names : Vec<_> = (&field_name).into_iter().map(|name| {
-        Ident::new(&format!("set_{}", name.to_string()), name.span())
+    let setter_names : Vec<_> = field_name.iter().map(|name| {
+        Ident::new(&format!("set_{}", name), name.span())
     }).collect();
 
-    let getter_names : Vec<_> = (&field_name).into_iter().map(|name| {
-        Ident::new(&format!("get_{}", name.to_string()), name.span())
+    let getter_names : Vec<_> = field_name.iter().map(|name| {
+        Ident::new(&format!("get_{}", name), name.span())
     }).collect();
 
-    let _tail_names : Vec<_> = (&field_name).into_iter().map(|name| {
-        Ident::new(&format!("pos_{}", name.to_string()), name.span())
+    let clear_names : Vec<_> = field_name.iter().map(|name| {
+        Ident::new(&format!("clear_{}", name), name.span())
+    }).collect();
+
+    let _tail_names : Vec<_> = field_name.iter().map(|name| {
+        Ident::new(&format!("pos_{}", name), name.span())
     }).collect();
     
-    let uids : Vec<_> = (0 .. field_name.len()).into_iter().map(|num| {
+    let uids : Vec<_> = (0 .. field_name.len()).map(|num| {
         ExprLit {
             attrs : vec![],
             lit : Lit::Int(LitInt::new(&num.to_string() , Span::call_site())),
@@ -78,12 +119,17 @@ pub fn generate_storage_ty(input: TokenStream) -> TokenStream {
         use ::iced::{
             Storage,
             StorageMem,
+            AsyncStorage,
+            AsyncStorageMem,
             RecordDesc,
-            Word,
             Error,
-            WORD_SIZE,
             StorageHasher32,
             InitStats,
+            HeaderFormat,
+            FullHeader,
+            FlushPolicy,
+            WriteBack,
+            FieldValue,
         };
 
         const MAX_RECORD_SZ : usize = 0x80;
@@ -94,69 +140,285 @@ pub fn generate_storage_ty(input: TokenStream) -> TokenStream {
         //    buf : [u8;VALUE_MAX_SZ],
         //}
 
-        pub struct #ty_name<M> {
-            storage      : Storage<M>,
+        /// Field value out of payload copied to `buf` of field size, bytes
+        /// which aren't a valid value are reported as corrupted record
+        fn field_from_payload<T : FieldValue, E>(copied : Result<Option<usize>, Error<E>>, buf : &[u8]) -> Result<Option<T>, Error<E>> {
+            assert_eq!(buf.len(), ::core::mem::size_of::<T>());
+            match copied {
+                // Record of other size can't hold the field
+                Err(Error::BufferTooSmall) => Err(Error::CorruptedRecordOnGet),
+                Ok(Some(len)) if len != buf.len() => Err(Error::CorruptedRecordOnGet),
+                Ok(Some(_)) => T::from_bytes(buf).map(Some).ok_or(Error::CorruptedRecordOnGet),
+                Ok(None) => Ok(None),
+                Err(e) => Err(e),
+            }
+        }
+
+        /// Bytes of field value, `FieldValue` has no padding
+        fn field_bytes<T : FieldValue>(value : &T) -> &[u8] {
+            unsafe {
+                ::core::slice::from_raw_parts(value as *const T as *const u8, ::core::mem::size_of::<T>())
+            }
+        }
+
+        /// Copy of cached field value. Every field is `FieldValue`, which is
+        /// `Copy`, so the cache adds no bound of its own
+        fn field_copy<T : FieldValue>(value : &T) -> T {
+            *value
+        }
+
+        // Fields set by transaction, they are written at once
+        pub struct #tx_ty_name {
+            #( #field_name : Option<#field_ty>, )*
+        }
+
+        impl #tx_ty_name {
+            fn new() -> Self {
+                Self {
+                    #( #field_name : None, )*
+                }
+            }
+
+            #( 
+                pub fn #setter_names(&mut self, #field_name : #field_ty) {
+                    self.#field_name = Some(#field_name);
+                }
+            )*
+
+            /// Tags and payloads of fields set
+            fn records(&self) -> ([(u32, &[u8]); MAX_RECORDS_NUMBER], usize) {
+                let mut records = [(0, &[][..]); MAX_RECORDS_NUMBER];
+                let mut len = 0;
+                #( 
+                    if let Some(value) = &self.#field_name {
+                        records[len] = (#uids, field_bytes(value));
+                        len += 1;
+                    }
+                )*
+                (records, len)
+            }
+        }
+
+        pub struct #ty_name<M : StorageMem, F = FullHeader> {
+            storage      : Storage<M, F>,
             record_table : [RecordDesc; MAX_RECORDS_NUMBER],
         }
 
         impl<M : StorageMem> #ty_name<M> {
             pub fn new(mem : M) -> Self {
+                Self::with_format(mem)
+            }
+        }
+
+        impl<M : StorageMem, F : HeaderFormat> #ty_name<M, F> {
+            pub fn with_format(mem : M) -> Self {
                 Self {
-                    storage : Storage::new(mem),
+                    storage : Storage::with_format(mem),
                     record_table : [
                         #(RecordDesc {
                             tag : #uids,
-                            ptr : None,
+                            offset : None,
                         }),*
                     ],
                 }
             }
 
-            pub fn init(&mut self, hasher : &mut impl StorageHasher32) -> InitStats {
+            pub fn init(&mut self, hasher : &mut impl StorageHasher32<M::Word>) -> Result<InitStats, Error<M::Error>> {
                 self.storage.init(&mut self.record_table, hasher)
             }
 
+            /// Number of setter calls skipped because field had the same value
+            pub fn skipped_writes(&self) -> usize {
+                self.storage.skipped_writes()
+            }
+
             #( 
-                pub fn #getter_names(&self) ->  Result<Option<&'static #field_ty>, Error> {
+                pub fn #getter_names(&self) ->  Result<Option<#field_ty>, Error<M::Error>> {
                     let record_desc = &self.record_table[#uids];
-                    let some = self.storage.get(record_desc)?;
-                    
-                    match some {
-                        Some(payload) => {
-                            unsafe {
-                                let field_ptr = payload.as_ptr() as usize as *const #field_ty;
-                                Ok(Some(&*field_ptr))
-                                //Ok(Some(&0))
-                            }
-                        }
-                        None => Ok(None),
+                    let mut buf = [0u8; ::core::mem::size_of::<#field_ty>()];
+                    let copied = self.storage.get_into(record_desc, &mut buf);
+                    field_from_payload(copied, &buf)
+                }
+            )*
+
+            #( 
+                pub fn #setter_names(&mut self, #field_name : #field_ty, hasher : &mut impl StorageHasher32<M::Word>) -> Result<(),Error<M::Error>> {
+                    let payload = field_bytes(&#field_name);
+
+                    self.storage.update_or_compact(&mut self.record_table, #uids, payload, hasher)
+                }
+            )*
+
+            #( 
+                pub fn #clear_names(&mut self, hasher : &mut impl StorageHasher32<M::Word>) -> Result<(),Error<M::Error>> {
+                    self.storage.remove_or_compact(&mut self.record_table, #uids, hasher)
+                }
+            )*
+
+            /// Set fields in one transaction: either every field set by `f`
+            /// is updated or none
+            pub fn transaction(&mut self, hasher : &mut impl StorageHasher32<M::Word>, f : impl FnOnce(&mut #tx_ty_name)) -> Result<(),Error<M::Error>> {
+                let mut tx = #tx_ty_name::new();
+                f(&mut tx);
+                let (records, len) = tx.records();
+                self.storage.update_all_or_compact(&mut self.record_table, &records[.. len], hasher)
+            }
+        }
+
+        // Async counterpart of storage type above, fields are stored the same way
+        pub struct #async_ty_name<M : AsyncStorageMem, F = FullHeader> {
+            storage      : AsyncStorage<M, F>,
+            record_table : [RecordDesc; MAX_RECORDS_NUMBER],
+        }
+
+        impl<M : AsyncStorageMem> #async_ty_name<M> {
+            pub fn new(mem : M) -> Self {
+                Self::with_format(mem)
+            }
+        }
+
+        impl<M : AsyncStorageMem, F : HeaderFormat> #async_ty_name<M, F> {
+            pub fn with_format(mem : M) -> Self {
+                Self {
+                    storage : AsyncStorage::with_format(mem),
+                    record_table : [
+                        #(RecordDesc {
+                            tag : #uids,
+                            offset : None,
+                        }),*
+                    ],
+                }
+            }
+
+            pub async fn init(&mut self, hasher : &mut impl StorageHasher32<M::Word>) -> Result<InitStats, Error<M::Error>> {
+                self.storage.init(&mut self.record_table, hasher).await
+            }
+
+            /// Number of setter calls skipped because field had the same value
+            pub fn skipped_writes(&self) -> usize {
+                self.storage.skipped_writes()
+            }
+
+            #( 
+                pub async fn #getter_names(&self) ->  Result<Option<#field_ty>, Error<M::Error>> {
+                    let record_desc = &self.record_table[#uids];
+                    let mut buf = [0u8; ::core::mem::size_of::<#field_ty>()];
+                    let copied = self.storage.get_into(record_desc, &mut buf).await;
+                    field_from_payload(copied, &buf)
+                }
+            )*
+
+            #( 
+                pub async fn #setter_names(&mut self, #field_name : #field_ty, hasher : &mut impl StorageHasher32<M::Word>) -> Result<(),Error<M::Error>> {
+                    let payload = field_bytes(&#field_name);
+
+                    self.storage.update_or_compact(&mut self.record_table, #uids, payload, hasher).await
+                }
+            )*
+
+            #( 
+                pub async fn #clear_names(&mut self, hasher : &mut impl StorageHasher32<M::Word>) -> Result<(),Error<M::Error>> {
+                    self.storage.remove_or_compact(&mut self.record_table, #uids, hasher).await
+                }
+            )*
+
+            /// Set fields in one transaction: either every field set by `f`
+            /// is updated or none
+            pub async fn transaction(&mut self, hasher : &mut impl StorageHasher32<M::Word>, f : impl FnOnce(&mut #tx_ty_name)) -> Result<(),Error<M::Error>> {
+                let mut tx = #tx_ty_name::new();
+                f(&mut tx);
+                let (records, len) = tx.records();
+                self.storage.update_all_or_compact(&mut self.record_table, &records[.. len], hasher).await
+            }
+        }
+
+        // Storage type above with write-back cache: setters only change RAM
+        // copy of field, dirty fields are written by flush in one transaction
+        pub struct #cached_ty_name<M : StorageMem, F = FullHeader> {
+            storage    : #ty_name<M, F>,
+            // Dirty fields
+            cache      : #tx_ty_name,
+            write_back : WriteBack,
+        }
+
+        impl<M : StorageMem> #cached_ty_name<M> {
+            pub fn new(mem : M, policy : FlushPolicy) -> Self {
+                Self::with_format(mem, policy)
+            }
+        }
+
+        impl<M : StorageMem, F : HeaderFormat> #cached_ty_name<M, F> {
+            pub fn with_format(mem : M, policy : FlushPolicy) -> Self {
+                Self {
+                    storage    : #ty_name::with_format(mem),
+                    cache      : #tx_ty_name::new(),
+                    write_back : WriteBack::new(policy),
+                }
+            }
+
+            /// Dirty fields are kept
+            pub fn init(&mut self, hasher : &mut impl StorageHasher32<M::Word>) -> Result<InitStats, Error<M::Error>> {
+                self.storage.init(hasher)
+            }
+
+            pub fn set_policy(&mut self, policy : FlushPolicy) {
+                self.write_back.set_policy(policy);
+            }
+
+            #( 
+                pub fn #getter_names(&self) ->  Result<Option<#field_ty>, Error<M::Error>> {
+                    match &self.cache.#field_name {
+                        Some(value) => Ok(Some(field_copy(value))),
+                        None => self.storage.#getter_names(),
                     }
                 }
             )*
 
             #( 
-                pub fn #setter_names(&mut self, #field_name : #field_ty, hasher : &mut impl StorageHasher32) -> Result<(),Error> {
-                    let mut record_desc = &mut self.record_table[#uids];
-                    
-                    let field_ptr : *const Word = (&#field_name) as *const _ as usize as *const Word;
-                    const FILED_SIZE : usize = ::core::mem::size_of::<#field_ty>();
-                    let payload_slice_sz : usize = match FILED_SIZE {
-                        0 => 0,
-                        n => {
-                            if n >= WORD_SIZE {
-                                n / WORD_SIZE
-                            } else {
-                                WORD_SIZE
-                            }
-                        }
-                    };
-                    let src_words : &[Word] = unsafe { ::core::slice::from_raw_parts(field_ptr, payload_slice_sz) };
-                    self.storage.update(record_desc, src_words, hasher)
+                /// Field is set at time `now`, see `FlushPolicy::max_age`
+                pub fn #setter_names(&mut self, #field_name : #field_ty, now : u64) {
+                    self.cache.#setter_names(#field_name);
+                    self.write_back.changed(now);
                 }
             )*
+
+            /// Names of fields not written to storage yet
+            pub fn dirty_fields(&self) -> impl Iterator<Item = &'static str> {
+                let dirty = [
+                    #( (stringify!(#field_name), self.cache.#field_name.is_some()), )*
+                ];
+                IntoIterator::into_iter(dirty).filter(|&(_, dirty)| dirty).map(|(name, _)| name)
+            }
+
+            pub fn is_dirty(&self) -> bool {
+                self.dirty_fields().next().is_some()
+            }
+
+            /// Write dirty fields to storage, fields set back to the stored
+            /// value are left out
+            pub fn flush(&mut self, hasher : &mut impl StorageHasher32<M::Word>) -> Result<(),Error<M::Error>> {
+                let (records, len) = self.cache.records();
+                self.storage.storage.update_all_or_compact(&mut self.storage.record_table, &records[.. len], hasher)?;
+                self.cache = #tx_ty_name::new();
+                self.write_back.flushed();
+                Ok(())
+            }
+
+            /// Flush if policy says changes are due at time `now`, returns
+            /// whether fields were written
+            pub fn poll_flush(&mut self, now : u64, hasher : &mut impl StorageHasher32<M::Word>) -> Result<bool,Error<M::Error>> {
+                if !self.write_back.is_due(now) {
+                    return Ok(false);
+                }
+                self.flush(hasher)?;
+                Ok(true)
+            }
         }
 
-        impl<M : StorageMem> ::core::fmt::Debug for #ty_name<M> {
+        impl<M : StorageMem, F : HeaderFormat> ::core::fmt::Debug for #ty_name<M, F>
+        where
+            M::Error : ::core::fmt::Debug,
+        {
             fn fmt(&self, f: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {
                     write!(f, "{} {{\n", stringify!(#ty_name))?;
                     #( 
@@ -1,6 +1,6 @@
 #[test]
 fn tests() {
-    let t = trybuild::TestCases::new();
+    let _t = trybuild::TestCases::new();
     //t.pass("tests/01-parse-header.rs");
     //t.pass("tests/02-parse-body.rs");
     //t.compile_fail("tests/03-expand-four-errors.rs");
@@ -0,0 +1,194 @@
+//! Async storage memory on top of `embedded-storage-async` NOR flash
+
+use core::cell::RefMut;
+use core::future::poll_fn;
+use core::mem::size_of_val;
+use core::task::Poll;
+
+use embedded_storage_async::nor_flash::{NorFlash, ErrorType};
+
+use crate::{AsyncStorageMem, NorFlashMem, Geometry, SectorRegion, Word, MAX_PROGRAM_UNIT_SIZE};
+use crate::nor_flash::READ_BUF_LEN;
+
+/// Adapter of `embedded-storage-async` NOR flash to `AsyncStorageMem`, see
+/// `NorFlashMem` for layout.
+pub struct AsyncNorFlashMem<F : ErrorType, W = u32>(NorFlashMem<F, W>);
+
+impl<F : NorFlash> AsyncNorFlashMem<F> {
+
+    pub fn new(flash : F, start : u32, sectors : &'static [SectorRegion]) -> Self {
+        Self::with_word(flash, start, sectors)
+    }
+}
+
+impl<F : NorFlash, W : Word> AsyncNorFlashMem<F, W> {
+
+    /// Flash addressed by words of given type
+    pub fn with_word(flash : F, start : u32, sectors : &'static [SectorRegion]) -> Self {
+        let capacity = flash.capacity();
+        Self(NorFlashMem::with_layout(flash, [F::READ_SIZE, F::WRITE_SIZE, F::ERASE_SIZE], capacity, start, sectors))
+    }
+
+    /// Give flash back
+    pub fn release(self) -> F {
+        self.0.release()
+    }
+
+    /// Borrow flash once read of other future is done
+    async fn flash(&self) -> RefMut<'_, F> {
+        poll_fn(|cx| match self.0.flash.try_borrow_mut() {
+            Ok(flash) => Poll::Ready(flash),
+            Err(_) => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }).await
+    }
+}
+
+impl<F : NorFlash, W : Word> AsyncStorageMem for AsyncNorFlashMem<F, W> {
+    type Word = W;
+    type Error = F::Error;
+
+    async fn write(&mut self, offset_words : usize, words : &[W]) -> Result<(), Self::Error> {
+        let mut buf = [0; MAX_PROGRAM_UNIT_SIZE];
+        let (address, bytes) = self.0.unit_bytes(offset_words, words, &mut buf);
+        self.0.flash.get_mut().write(address, bytes).await
+    }
+
+    // Flash is borrowed only by `flash`, which waits for other reads
+    #[allow(clippy::await_holding_refcell_ref)]
+    async fn read_into(&self, offset_words : usize, words : &mut [W]) -> Result<(), Self::Error> {
+        let mut buf = [0; READ_BUF_LEN];
+        for (address, chunk) in self.0.read_chunks(offset_words, words) {
+            let bytes = &mut buf[.. size_of_val(chunk)];
+            self.flash().await.read(address, bytes).await?;
+            self.0.read_done(bytes, chunk);
+        }
+        Ok(())
+    }
+
+    async fn erase(&mut self, sector : usize) -> Result<(), Self::Error> {
+        let (from, to) = self.0.sector_range(sector);
+        self.0.flash.get_mut().erase(from, to).await
+    }
+
+    fn geometry(&self) -> Geometry<W> {
+        self.0.flash_geometry(F::WRITE_SIZE)
+    }
+
+    fn len(&self) -> usize {
+        self.0.flash_len()
+    }
+}
+
+// Mock flash yields to executor before every operation
+#[cfg(any(test, feature="test-def"))]
+mod mock {
+    use embedded_storage_async::nor_flash::{NorFlash, ReadNorFlash};
+    use crate::MockFlash;
+    use crate::test_def::yield_now;
+
+    impl<const N : usize> ReadNorFlash for MockFlash<N> {
+        const READ_SIZE : usize = 1;
+
+        async fn read(&mut self, offset : u32, bytes : &mut [u8]) -> Result<(), Self::Error> {
+            yield_now().await;
+            embedded_storage::nor_flash::ReadNorFlash::read(self, offset, bytes)
+        }
+
+        fn capacity(&self) -> usize {
+            N
+        }
+    }
+
+    impl<const N : usize> NorFlash for MockFlash<N> {
+        const WRITE_SIZE : usize = 8;
+        const ERASE_SIZE : usize = 0x100;
+
+        async fn erase(&mut self, from : u32, to : u32) -> Result<(), Self::Error> {
+            yield_now().await;
+            embedded_storage::nor_flash::NorFlash::erase(self, from, to)
+        }
+
+        async fn write(&mut self, offset : u32, bytes : &[u8]) -> Result<(), Self::Error> {
+            yield_now().await;
+            embedded_storage::nor_flash::NorFlash::write(self, offset, bytes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AsyncStorage, Storage, NorFlashMem, MockFlash, RecordDesc, block_on};
+    use core::future::Future;
+    use core::pin::pin;
+    use crc::crc32::{Digest, IEEE};
+    use crc::CalcType;
+
+    const SECTORS : &[SectorRegion] = &[
+        SectorRegion { count : 4, sector_len : 0x40 },
+    ];
+
+    #[test]
+    fn async_nor_flash_test() {
+        let mut crc32 = Digest::new_custom(IEEE, !0u32, 0u32, CalcType::Normal);
+        let mut desc_list = [
+            RecordDesc {
+                tag : 0,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 1,
+                offset : None,
+            },
+        ];
+        let mut sync_list = desc_list;
+
+        let mut sync_storage = Storage::new(NorFlashMem::new(MockFlash::<0x800>::new(), 0x100, SECTORS));
+        let mut storage = AsyncStorage::new(AsyncNorFlashMem::new(MockFlash::<0x800>::new(), 0x100, SECTORS));
+        sync_storage.init(&mut sync_list, &mut crc32).unwrap();
+        block_on(storage.init(&mut desc_list, &mut crc32)).unwrap();
+        for counter in 0 .. 100u8 {
+            sync_storage.update_or_compact(&mut sync_list, (counter % 2) as u32, &[counter; 9], &mut crc32).unwrap();
+            block_on(storage.update_or_compact(&mut desc_list, (counter % 2) as u32, &[counter; 9], &mut crc32)).unwrap();
+        }
+        assert_eq!(storage.log.storage.0.0.flash.borrow().mem[..], sync_storage.storage.release().mem[..]);
+
+        let mut storage = AsyncStorage::new(AsyncNorFlashMem::new(storage.log.storage.0.release(), 0x100, SECTORS));
+        let stats = block_on(storage.init(&mut desc_list, &mut crc32)).unwrap();
+        assert_eq!(stats.unique_tags, 2);
+        let mut buf = [0; 9];
+        assert_eq!(block_on(storage.get_into(&desc_list[0], &mut buf)).unwrap(), Some(9));
+        assert_eq!(buf, [98; 9]);
+        assert_eq!(block_on(storage.get_into(&desc_list[1], &mut buf)).unwrap(), Some(9));
+        assert_eq!(buf, [99; 9]);
+
+        // Reads of two futures at once take turns on flash
+        let (mut buf0, mut buf1) = ([0; 9], [0; 9]);
+        {
+            let mut read0 = pin!(storage.get_into(&desc_list[0], &mut buf0));
+            let mut read1 = pin!(storage.get_into(&desc_list[1], &mut buf1));
+            let (mut copied0, mut copied1) = (None, None);
+            block_on(poll_fn(|cx| {
+                if copied0.is_none() {
+                    if let Poll::Ready(res) = read0.as_mut().poll(cx) {
+                        copied0 = Some(res.unwrap());
+                    }
+                }
+                if copied1.is_none() {
+                    if let Poll::Ready(res) = read1.as_mut().poll(cx) {
+                        copied1 = Some(res.unwrap());
+                    }
+                }
+                match (copied0, copied1) {
+                    (Some(_), Some(_)) => Poll::Ready(()),
+                    _ => Poll::Pending,
+                }
+            }));
+            assert_eq!((copied0, copied1), (Some(Some(9)), Some(Some(9))));
+        }
+        assert_eq!((buf0, buf1), ([98; 9], [99; 9]));
+    }
+}
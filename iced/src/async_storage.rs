@@ -0,0 +1,205 @@
+//! Record log on storage memory with async operations
+
+use crate::{Mem, Storage, StorageHasher32, HeaderFormat, FullHeader, RecordDesc, RecordIndex, InitStats, Error, Geometry, Word};
+
+/// Storage memory which operations complete asynchronously, so executor
+/// isn't blocked while flash is programmed or erased. Contract is the same
+/// as of `StorageMem`.
+#[allow(async_fn_in_trait)]
+pub trait AsyncStorageMem {
+    /// Addressing unit, all offsets and sizes are in words of this type
+    type Word : Word;
+    type Error;
+    /// Program one unit, `offset_words` is aligned to `Geometry::program_unit`
+    /// and `words` is exactly one unit long
+    async fn write(&mut self, offset_words : usize, words : &[Self::Word]) -> Result<(), Self::Error>;
+    /// Copy words starting at `offset_words` to `words`, reads of several
+    /// futures may be in progress at once
+    async fn read_into(&self, offset_words : usize, words : &mut [Self::Word]) -> Result<(), Self::Error>;
+    /// Erase sector, after that every word of it reads as `Geometry::erased`
+    async fn erase(&mut self, sector : usize) -> Result<(), Self::Error>;
+    fn geometry(&self) -> Geometry<Self::Word>;
+    /// Memory size in words
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Async storage memory seen by record log engine
+pub(crate) struct AsyncMem<A>(pub(crate) A);
+
+impl<A : AsyncStorageMem> Mem for AsyncMem<A> {
+    type Word = A::Word;
+    type Error = A::Error;
+
+    async fn mem_write(&mut self, offset_words : usize, words : &[A::Word]) -> Result<(), A::Error> {
+        self.0.write(offset_words, words).await
+    }
+
+    async fn mem_read_into(&self, offset_words : usize, words : &mut [A::Word]) -> Result<(), A::Error> {
+        self.0.read_into(offset_words, words).await
+    }
+
+    async fn mem_erase(&mut self, sector : usize) -> Result<(), A::Error> {
+        self.0.erase(sector).await
+    }
+
+    fn mem_geometry(&self) -> Geometry<A::Word> {
+        self.0.geometry()
+    }
+
+    fn mem_len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+/// Async counterpart of `Storage`, records are laid out the same way.
+///
+/// Every operation which writes takes `&mut self`, so storage memory is never
+/// written by two futures at once. Reads take `&self` like in `Storage`.
+pub struct AsyncStorage<A, F = FullHeader> {
+    pub(crate) log : Storage<AsyncMem<A>, F>,
+}
+
+impl<A : AsyncStorageMem> AsyncStorage<A> {
+
+    pub fn new(storage : A) -> Self {
+        Self::with_format(storage)
+    }
+}
+
+impl<A : AsyncStorageMem, F : HeaderFormat> AsyncStorage<A, F> {
+
+    /// Storage with records of given header format
+    pub fn with_format(storage : A) -> Self {
+        Self { log : Storage::with_format(AsyncMem(storage)) }
+    }
+
+    /// See `Storage::set_verify`
+    pub fn set_verify(&mut self, verify : bool) {
+        self.log.set_verify(verify);
+    }
+
+    /// See `Storage::set_drop_unknown`
+    pub fn set_drop_unknown(&mut self, drop_unknown : bool) {
+        self.log.set_drop_unknown(drop_unknown);
+    }
+
+    /// See `Storage::skipped_writes`
+    pub fn skipped_writes(&self) -> usize {
+        self.log.skipped_writes()
+    }
+
+    /// See `Storage::init`
+    pub async fn init(&mut self, list : &mut (impl RecordIndex + ?Sized), hasher : &mut impl StorageHasher32<A::Word>) -> Result<InitStats,Error<A::Error>> {
+        self.init_with(list, hasher, |_| {}).await
+    }
+
+    /// See `Storage::init_with`
+    pub async fn init_with(&mut self, list : &mut (impl RecordIndex + ?Sized), hasher : &mut impl StorageHasher32<A::Word>, mut on_unknown : impl FnMut(RecordDesc)) -> Result<InitStats,Error<A::Error>> {
+        self.log.init_async(list, hasher, &mut on_unknown).await
+    }
+
+    /// See `Storage::update`
+    pub async fn update(&mut self, record : &mut RecordDesc, payload : &[u8], hasher : &mut impl StorageHasher32<A::Word>) -> Result<(),Error<A::Error>> {
+        self.log.update_async(record, payload, hasher).await
+    }
+
+    /// See `Storage::update_or_compact`
+    pub async fn update_or_compact(&mut self, list : &mut (impl RecordIndex + ?Sized), tag : u32, payload : &[u8], hasher : &mut impl StorageHasher32<A::Word>) -> Result<(),Error<A::Error>> {
+        self.log.update_or_compact_async(list, tag, payload, hasher).await
+    }
+
+    /// See `Storage::update_all_or_compact`
+    pub async fn update_all_or_compact(&mut self, list : &mut (impl RecordIndex + ?Sized), records : &[(u32, &[u8])], hasher : &mut impl StorageHasher32<A::Word>) -> Result<(),Error<A::Error>> {
+        self.log.update_all_or_compact_async(list, records, hasher).await
+    }
+
+    /// See `Storage::remove`
+    pub async fn remove(&mut self, record : &mut RecordDesc, hasher : &mut impl StorageHasher32<A::Word>) -> Result<(),Error<A::Error>> {
+        self.log.remove_async(record, hasher).await
+    }
+
+    /// See `Storage::remove_or_compact`
+    pub async fn remove_or_compact(&mut self, list : &mut (impl RecordIndex + ?Sized), tag : u32, hasher : &mut impl StorageHasher32<A::Word>) -> Result<(),Error<A::Error>> {
+        self.log.remove_or_compact_async(list, tag, hasher).await
+    }
+
+    /// See `Storage::compact`
+    pub async fn compact(&mut self, list : &mut (impl RecordIndex + ?Sized), hasher : &mut impl StorageHasher32<A::Word>) -> Result<(),Error<A::Error>> {
+        self.log.compact_async(list, hasher).await
+    }
+
+    /// Copy record payload to `buf`, returns payload size in bytes
+    pub async fn get_into(&self, record : &RecordDesc, buf : &mut [u8]) -> Result<Option<usize>,Error<A::Error>> {
+        self.log.get_into_async(record, buf).await
+    }
+
+    /// Total amount of occupied storage space in bytes
+    pub async fn len(&self) -> Result<usize,Error<A::Error>> {
+        self.log.len_async().await
+    }
+    pub async fn is_empty(&self) -> Result<bool,Error<A::Error>> {
+        Ok(self.len().await? == 0)
+    }
+
+    /// Total storage space in bytes, retired sectors excluded
+    pub async fn capacity(&self) -> Result<usize,Error<A::Error>> {
+        self.log.capacity_async().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TestMem, YieldMem, block_on};
+    use crc::crc32::{Digest, IEEE};
+    use crc::CalcType;
+
+    #[test]
+    fn async_storage_test() {
+        let mut crc32 = Digest::new_custom(IEEE, !0u32, 0u32, CalcType::Normal);
+        let mut desc_list = [
+            RecordDesc {
+                tag : 0,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 1,
+                offset : None,
+            },
+        ];
+        let mut sync_list = desc_list;
+
+        let mut sync_storage = Storage::new(TestMem::new());
+        let mut storage = AsyncStorage::new(YieldMem::new(TestMem::new()));
+        sync_storage.set_verify(true);
+        storage.set_verify(true);
+        sync_storage.init(&mut sync_list, &mut crc32).unwrap();
+        block_on(storage.init(&mut desc_list, &mut crc32)).unwrap();
+
+        sync_storage.update(&mut sync_list[1], &[1, 2, 3], &mut crc32).unwrap();
+        block_on(storage.update(&mut desc_list[1], &[1, 2, 3], &mut crc32)).unwrap();
+        for counter in 0 .. 50u8 {
+            sync_storage.update_or_compact(&mut sync_list, 0, &[counter; 6], &mut crc32).unwrap();
+            block_on(storage.update_or_compact(&mut desc_list, 0, &[counter; 6], &mut crc32)).unwrap();
+        }
+
+        // Every memory operation has yielded to executor
+        assert!(storage.log.storage.0.yields.get() > 0);
+        // Records are the same as written by sync storage
+        assert_eq!(storage.log.storage.0.mem.0, sync_storage.storage.0);
+        assert_eq!(block_on(storage.len()).unwrap(), sync_storage.len().unwrap());
+        assert_eq!(block_on(storage.capacity()).unwrap(), sync_storage.capacity().unwrap());
+
+        let stats = block_on(storage.init(&mut desc_list, &mut crc32)).unwrap();
+        assert_eq!(stats.unique_tags, 2);
+        assert_eq!(stats.words_wasted, 0);
+        let mut buf = [0; 6];
+        assert_eq!(block_on(storage.get_into(&desc_list[0], &mut buf)).unwrap(), Some(6));
+        assert_eq!(buf, [49; 6]);
+        assert_eq!(block_on(storage.get_into(&desc_list[1], &mut buf)).unwrap(), Some(3));
+        assert_eq!(&buf[.. 3], &[1, 2, 3]);
+    }
+}
@@ -0,0 +1,78 @@
+use core::convert::TryInto;
+use core::mem::size_of;
+
+/// Plain data type storage field can hold. Field is written as its bytes in
+/// memory and read back only through `from_bytes`, so bytes found in flash
+/// never become invalid value, e.g. `bool` other than 0 or 1. Value in flash
+/// is a bitwise copy, hence `Copy`: type owning resources can't be a field.
+///
+/// # Safety
+///
+/// Type must have no padding bytes, every byte of value is written to flash.
+pub unsafe trait FieldValue : Copy {
+    /// Value out of `size_of::<Self>()` bytes, `None` if they don't form a
+    /// valid one
+    fn from_bytes(bytes : &[u8]) -> Option<Self>;
+}
+
+macro_rules! impl_from_ne_bytes {
+    ($($ty:ty),*) => {
+        $(
+            unsafe impl FieldValue for $ty {
+                fn from_bytes(bytes : &[u8]) -> Option<Self> {
+                    Some(<$ty>::from_ne_bytes(bytes.try_into().ok()?))
+                }
+            }
+        )*
+    };
+}
+
+impl_from_ne_bytes!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+
+unsafe impl FieldValue for bool {
+    fn from_bytes(bytes : &[u8]) -> Option<Self> {
+        match bytes {
+            [0] => Some(false),
+            [1] => Some(true),
+            _ => None,
+        }
+    }
+}
+
+unsafe impl FieldValue for char {
+    fn from_bytes(bytes : &[u8]) -> Option<Self> {
+        char::from_u32(u32::from_bytes(bytes)?)
+    }
+}
+
+// Array elements follow each other without gaps
+unsafe impl<T : FieldValue, const N : usize> FieldValue for [T; N] {
+    fn from_bytes(bytes : &[u8]) -> Option<Self> {
+        let size = size_of::<T>();
+        if bytes.len() != size * N {
+            return None;
+        }
+        let element = |i : usize| T::from_bytes(&bytes[i * size .. (i + 1) * size]);
+        if (0 .. N).any(|i| element(i).is_none()) {
+            return None;
+        }
+        Some(core::array::from_fn(|i| element(i).unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_bytes_test() {
+        assert_eq!(u32::from_bytes(&0x1234_5678u32.to_ne_bytes()), Some(0x1234_5678));
+        assert_eq!(u32::from_bytes(&[0; 3]), None);
+        assert_eq!(bool::from_bytes(&[1]), Some(true));
+        assert_eq!(bool::from_bytes(&[2]), None);
+        assert_eq!(char::from_bytes(&0xd800u32.to_ne_bytes()), None);
+        assert_eq!(<[bool; 3]>::from_bytes(&[0, 1, 0]), Some([false, true, false]));
+        assert_eq!(<[bool; 3]>::from_bytes(&[0, 1, 0xff]), None);
+        assert_eq!(<[u16; 2]>::from_bytes(&[0; 3]), None);
+    }
+}
@@ -0,0 +1,191 @@
+//! Record descriptor tables looked up by tag
+
+use crate::RecordDesc;
+
+/// Table of record descriptors which storage keeps up to date. Tags are
+/// arbitrary, records with tags missing in the table are left alone by
+/// `init` and compaction, see `Storage::set_drop_unknown`.
+pub trait RecordIndex {
+    /// Every descriptor of the table
+    fn records(&self) -> &[RecordDesc];
+    fn records_mut(&mut self) -> &mut [RecordDesc];
+    /// Position of descriptor with given tag in `records`
+    fn position(&self, tag : u32) -> Option<usize>;
+
+    fn find(&self, tag : u32) -> Option<&RecordDesc> {
+        let pos = self.position(tag)?;
+        Some(&self.records()[pos])
+    }
+
+    fn find_mut(&mut self, tag : u32) -> Option<&mut RecordDesc> {
+        let pos = self.position(tag)?;
+        Some(&mut self.records_mut()[pos])
+    }
+
+    /// Table can be looked up by `position`, `init` fails otherwise
+    fn is_consistent(&self) -> bool {
+        true
+    }
+}
+
+/// Slice sorted by tag, dense tags `0 .. len` are looked up directly. Other
+/// tags are binary searched, so slice must be sorted by unique tags.
+impl RecordIndex for [RecordDesc] {
+    fn records(&self) -> &[RecordDesc] {
+        self
+    }
+
+    fn records_mut(&mut self) -> &mut [RecordDesc] {
+        self
+    }
+
+    fn position(&self, tag : u32) -> Option<usize> {
+        match self.get(tag as usize) {
+            Some(record) if record.tag == tag => Some(tag as usize),
+            _ => self.binary_search_by_key(&tag, |record| record.tag).ok(),
+        }
+    }
+
+    fn is_consistent(&self) -> bool {
+        self.windows(2).all(|pair| pair[0].tag < pair[1].tag)
+    }
+}
+
+impl<const N : usize> RecordIndex for [RecordDesc; N] {
+    fn records(&self) -> &[RecordDesc] {
+        self
+    }
+
+    fn records_mut(&mut self) -> &mut [RecordDesc] {
+        self
+    }
+
+    fn position(&self, tag : u32) -> Option<usize> {
+        self[..].position(tag)
+    }
+
+    fn is_consistent(&self) -> bool {
+        self[..].is_consistent()
+    }
+}
+
+// Slot of `TagMap` not taken by any tag
+const EMPTY_SLOT : usize = usize::MAX;
+
+/// Open addressing map of up to `N` records, tags are added in any order
+#[derive(Clone, Debug)]
+pub struct TagMap<const N : usize> {
+    records : [RecordDesc; N],
+    len     : usize,
+    // Positions in `records`, probed linearly from tag hash
+    slots   : [usize; N],
+}
+
+impl<const N : usize> TagMap<N> {
+    pub fn new() -> Self {
+        Self {
+            records : [RecordDesc { tag : 0, offset : None }; N],
+            len     : 0,
+            slots   : [EMPTY_SLOT; N],
+        }
+    }
+
+    /// Add record with given tag, returns its position in `records` or
+    /// `None` if map is full
+    pub fn insert(&mut self, tag : u32) -> Option<usize> {
+        let mut free = None;
+        for slot in Self::probe(tag) {
+            match self.slots[slot] {
+                EMPTY_SLOT => {
+                    free = Some(slot);
+                    break;
+                }
+                pos if self.records[pos].tag == tag => return Some(pos),
+                _ => {}
+            }
+        }
+        let slot = free?;
+        let pos = self.len;
+        self.records[pos] = RecordDesc { tag, offset : None };
+        self.slots[slot] = pos;
+        self.len += 1;
+        Some(pos)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Every slot once, starting from Fibonacci hash of tag
+    fn probe(tag : u32) -> impl Iterator<Item = usize> {
+        let start = (tag.wrapping_mul(0x9E37_79B9) as usize) % N.max(1);
+        (0 .. N).map(move |n| (start + n) % N)
+    }
+}
+
+impl<const N : usize> Default for TagMap<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N : usize> RecordIndex for TagMap<N> {
+    fn records(&self) -> &[RecordDesc] {
+        &self.records[.. self.len]
+    }
+
+    fn records_mut(&mut self) -> &mut [RecordDesc] {
+        &mut self.records[.. self.len]
+    }
+
+    fn position(&self, tag : u32) -> Option<usize> {
+        for slot in Self::probe(tag) {
+            match self.slots[slot] {
+                EMPTY_SLOT => return None,
+                pos if self.records[pos].tag == tag => return Some(pos),
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tag_map_test() {
+        let mut map = TagMap::<4>::new();
+        assert_eq!(map.insert(0xDEAD_BEEF), Some(0));
+        assert_eq!(map.insert(7), Some(1));
+        assert_eq!(map.insert(0x1000_0007), Some(2));
+        assert_eq!(map.insert(7), Some(1));
+        assert_eq!(map.insert(0), Some(3));
+        assert_eq!(map.insert(1), None);
+        assert_eq!(map.len(), 4);
+
+        map.find_mut(0x1000_0007).unwrap().offset = Some(12);
+        assert_eq!(map.find(0x1000_0007), Some(&RecordDesc { tag : 0x1000_0007, offset : Some(12) }));
+        assert_eq!(map.position(0xDEAD_BEEF), Some(0));
+        assert_eq!(map.position(0), Some(3));
+        assert_eq!(map.position(1), None);
+
+        let list = [
+            RecordDesc { tag : 3, offset : None },
+            RecordDesc { tag : 40, offset : None },
+            RecordDesc { tag : 0xFFFF_0000, offset : None },
+        ];
+        assert_eq!(list.position(40), Some(1));
+        assert_eq!(list.position(0xFFFF_0000), Some(2));
+        assert_eq!(list.position(2), None);
+        assert_eq!(list[.. 2].position(3), Some(0));
+        assert!(list.is_consistent());
+        assert!(![list[1], list[0]].is_consistent());
+        assert!(![list[0], list[0]].is_consistent());
+    }
+}
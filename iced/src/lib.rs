@@ -1,389 +1,3207 @@
 #![no_std]
 #![allow(dead_code, unused_imports)]
 
-pub use iced_macros::generate_storage_ty;
+pub use iced_macros::{generate_storage_ty, FieldValue};
 
+use core::fmt::Debug;
+use core::future::Future;
+use core::marker::PhantomData;
 use core::mem::size_of;
+use core::pin::pin;
+use core::task::{Context, Poll, Waker};
 use core::slice::{from_raw_parts_mut, from_raw_parts};
 
-// TODO: implement errors and error tests
+mod field;
+pub use field::FieldValue;
+
+mod index;
+pub use index::{RecordIndex, TagMap};
+
+mod records;
+pub use records::{LogRecord, Records, History};
+use records::LogPos;
+
+mod transaction;
+pub use transaction::{Transaction, MAX_TX_RECORDS};
+
+mod write_back;
+pub use write_back::{FlushPolicy, WriteBack};
+
+mod async_storage;
+pub use async_storage::{AsyncStorage, AsyncStorageMem};
+
+#[cfg(any(test, feature="embedded-storage"))]
+mod nor_flash;
+#[cfg(any(test, feature="embedded-storage"))]
+pub use nor_flash::NorFlashMem;
+#[cfg(all(any(test, feature="embedded-storage"), any(test, feature="test-def")))]
+pub use nor_flash::MockFlash;
+
+#[cfg(any(test, feature="embedded-storage-async"))]
+mod async_nor_flash;
+#[cfg(any(test, feature="embedded-storage-async"))]
+pub use async_nor_flash::AsyncNorFlashMem;
+
 // TODO: validity check on fn get
 
-// Minimal addressing unit (and aligment)
-pub type Word = u32;
-// Header len in words
-const HEADER_LEN : usize = size_of::<Header>() / size_of::<Word>();
-// Word size in bytes
-pub const WORD_SIZE : usize = size_of::<Word>();
-
-#[repr(C)]
-#[derive(PartialEq, Eq, Debug)]
-pub struct Header {
-    tag  : Word,
-    /// Size of payload in words
-    sz   : Word,
-    crc  : u32,
+// Record and page header fields are 32-bit, they take as many words of
+// storage memory as needed
+const FIELD_SIZE : usize = size_of::<u32>();
+// Largest record header len in fields
+pub const MAX_HEADER_LEN : usize = 4;
+// Largest supported program unit in bytes (256-bit flash word)
+pub const MAX_PROGRAM_UNIT_SIZE : usize = 32;
+/// Words read from storage memory at once while streaming records
+const READ_CHUNK : usize = 16;
+// Commit field follows record payload and is programmed last, so record
+// is valid only when its write was not interrupted
+const COMMIT_LEN : usize = 1;
+// Value of programmed commit field
+const RECORD_COMMITTED : u32 = 0x5A5A_C3C3;
+// Commit field value of record which failed read back verification
+const RECORD_DISCARDED : u32 = 0x0BAD_0BAD;
+// Commit field value of tombstone: empty record which removes the tag
+const RECORD_REMOVED : u32 = 0x7E4D_7E4D;
+// Commit field value of record written by transaction, it is applied only
+// when transaction commit record follows
+const RECORD_STAGED : u32 = 0x57A6_57A6;
+// Commit field value of transaction commit record: empty record which tag
+// field is number of records staged by transaction
+const RECORD_TX_COMMIT : u32 = 0x7C0A_7C0A;
+// Number of attempts to write record when verification fails
+const WRITE_ATTEMPTS : usize = 3;
+// Page header layout, every field takes its own program unit. Every field
+// is programmed only once, so page state only moves forward by programming
+// erased fields:
+// ERASED -> RECEIVING -> ACTIVE -> OBSOLETE
+// Worn page gets BAD marker and is never erased again, so the marker
+// persists. Active page with the marker is retiring: it is still scanned,
+// but takes no new records.
+const PAGE_SEQ : usize = 0;
+const PAGE_RECEIVING : usize = 1;
+const PAGE_ACTIVE : usize = 2;
+const PAGE_OBSOLETE : usize = 3;
+const PAGE_BAD : usize = 4;
+// Page header len in fields
+const PAGE_HEADER_LEN : usize = 5;
+// Value of programmed page state field
+const PAGE_STATE_SET : u32 = 0xC0DE_A5A5;
+
+/// Minimal addressing unit of storage memory
+pub trait Word : Copy + Eq + Default + Debug + 'static {
+    /// Word of native endian bytes, missing bytes are zero
+    fn from_bytes(bytes : &[u8]) -> Self;
+    /// Store native endian bytes of word, as many as fit into `bytes`
+    fn to_bytes(self, bytes : &mut [u8]);
+}
+
+macro_rules! impl_word {
+    ($($ty:ty),*) => {
+        $(
+            impl Word for $ty {
+                fn from_bytes(bytes : &[u8]) -> Self {
+                    let mut buf = [0; size_of::<$ty>()];
+                    buf[.. bytes.len()].copy_from_slice(bytes);
+                    <$ty>::from_ne_bytes(buf)
+                }
+
+                fn to_bytes(self, bytes : &mut [u8]) {
+                    let len = bytes.len().min(size_of::<$ty>());
+                    bytes[.. len].copy_from_slice(&self.to_ne_bytes()[.. len]);
+                }
+            }
+        )*
+    };
+}
+
+impl_word!(u8, u16, u32, u64);
+
+/// State of record found during scan
+#[derive(Copy, Clone)]
+enum RecordState {
+    /// Nothing was written yet
+    Erased,
+    /// Record write was interrupted, record takes given number of words
+    Torn(usize),
+    /// Checksum mismatch, record takes given number of words
+    Corrupted(usize),
+    /// Record failed write verification, record takes given number of words
+    Discarded(usize),
+    /// Record of given tag is complete and valid, record takes given number
+    /// of words
+    Committed(u32, usize),
+    /// Valid tombstone of given tag, record takes given number of words
+    Removed(u32, usize),
+    /// Valid record of given tag staged by transaction, record takes given
+    /// number of words
+    Staged(u32, usize),
+    /// Commit of transaction which has staged given number of records,
+    /// record takes given number of words
+    TxCommit(u32, usize),
+    /// Not a record
+    Garbage,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum PageState {
+    /// Whole page is erased
+    Erased,
+    /// Page is being opened
+    Receiving,
+    /// Page holds records
+    Active,
+    /// Page content was moved to another page
+    Obsolete,
+    /// Page content is not recognized
+    Corrupted,
+    /// Page is worn out and excluded from use
+    Bad,
+}
+
+/// Record header fields
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct HeaderFields {
+    pub tag : u32,
+    /// Size of payload in bytes
+    pub sz  : u32,
+    /// Record checksum, only `HeaderFormat::CRC_MASK` bits of it are kept
+    pub crc : u32,
+}
+
+/// Record header layout.
+///
+/// Header starts with sync field: magic bits and header check. Header is
+/// programmed sync field last, so once sync field is valid, record size can
+/// be trusted and scanner never looks into payload.
+pub trait HeaderFormat {
+    /// Header len in 32-bit fields, at most `MAX_HEADER_LEN`
+    const LEN : usize;
+    /// Magic bits of sync field
+    const SYNC : u32;
+    const SYNC_MASK : u32;
+    /// Largest tag and payload size
+    const MAX_TAG : u32;
+    const MAX_SZ : u32;
+    /// Checksum bits kept in header
+    const CRC_MASK : u32;
+
+    /// Header fields, `check` is hash of tag and size
+    fn encode(fields : &HeaderFields, check : u32) -> [u32; MAX_HEADER_LEN];
+    /// Header fields, not validated
+    fn decode(words : &[u32]) -> HeaderFields;
+}
+
+/// Header of four fields: sync, tag, size and full checksum
+pub struct FullHeader;
+
+impl HeaderFormat for FullHeader {
+    const LEN : usize = 4;
+    const SYNC : u32 = 0x1CED_0000;
+    const SYNC_MASK : u32 = 0xFFFF_0000;
+    const MAX_TAG : u32 = u32::MAX;
+    const MAX_SZ : u32 = u32::MAX;
+    const CRC_MASK : u32 = u32::MAX;
+
+    fn encode(fields : &HeaderFields, check : u32) -> [u32; MAX_HEADER_LEN] {
+        let sync = Self::SYNC | (check & !Self::SYNC_MASK);
+        [sync, fields.tag, fields.sz, fields.crc]
+    }
+
+    fn decode(words : &[u32]) -> HeaderFields {
+        HeaderFields { tag : words[1], sz : words[2], crc : words[3] }
+    }
+}
+
+/// Header of two fields for small records: sync field with 8-bit header check
+/// and 16-bit checksum, then 16-bit tag and 16-bit size
+pub struct CompactHeader;
+
+impl HeaderFormat for CompactHeader {
+    const LEN : usize = 2;
+    const SYNC : u32 = 0xC500_0000;
+    const SYNC_MASK : u32 = 0xFF00_0000;
+    const MAX_TAG : u32 = 0xFFFF;
+    const MAX_SZ : u32 = 0xFFFF;
+    const CRC_MASK : u32 = 0xFFFF;
+
+    fn encode(fields : &HeaderFields, check : u32) -> [u32; MAX_HEADER_LEN] {
+        let sync = Self::SYNC | (check & 0xFF) << 16 | (fields.crc & Self::CRC_MASK);
+        [sync, fields.tag << 16 | fields.sz, 0, 0]
+    }
+
+    fn decode(words : &[u32]) -> HeaderFields {
+        HeaderFields { tag : words[1] >> 16, sz : words[1] & 0xFFFF, crc : words[0] & Self::CRC_MASK }
+    }
 }
 
 #[derive(Debug)]
-pub enum Error {
+pub enum Error<E> {
     OutOfFreeSpace,
     CorruptedRecordOnGet,
+    /// Caller buffer can't take record payload
+    BufferTooSmall,
+    /// Record read back doesn't match written one after every attempt in
+    /// every page
+    WriteVerifyFailed,
+    /// Storage is written before `init` has found the head page
+    NotInitialized,
+    /// Record can't fit into any page, compaction won't help
+    RecordTooLarge,
+    /// Tag doesn't fit record header, see `HeaderFormat::MAX_TAG`
+    TagOutOfRange,
+    /// Record table can't be looked up, e.g. slice isn't sorted by tag
+    InconsistentTable,
+    /// Transaction stages more than `MAX_TX_RECORDS` records
+    TooManyTxRecords,
+    /// Transaction updates record with tag which isn't in record table
+    UnknownTag,
+    /// Storage memory backend failed
+    Mem(E),
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct RecordDesc {
-    pub tag : Word,
-    pub ptr : Option<&'static Header>,
+    pub tag    : u32,
+    /// Position of the latest record header in storage memory, in words
+    pub offset : Option<usize>,
+}
+
+/// Payload of record being appended
+#[derive(Copy, Clone)]
+enum Payload<'a> {
+    /// Bytes given by caller
+    Bytes(&'a [u8]),
+    /// Payload of record with header at given position, record is copied
+    /// as is
+    Stored(usize),
 }
 
 #[derive(Debug)]
 pub struct InitStats {
-    words_wasted : usize,
-    unique_tags  : usize,
+    pub words_wasted : usize,
+    pub unique_tags  : usize,
+    /// Records which write was interrupted
+    pub torn_records : usize,
+    /// Complete records with checksum mismatch
+    pub corrupted_records : usize,
+    /// Records discarded by write verification
+    pub discarded_records : usize,
+    /// Records which tag isn't in the table, every version is counted
+    pub unknown_records : usize,
+    /// Sectors retired because of wear, including retiring ones
+    pub retired_sectors : usize,
+    /// Capacity of retired sectors in words
+    pub retired_words : usize,
+    /// Updates skipped since storage was created, because record already
+    /// had the same payload
+    pub skipped_writes : usize,
+}
+
+/// Run of equally sized sectors
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct SectorRegion {
+    /// Number of sectors in run
+    pub count      : usize,
+    /// Sector (minimal erasable unit) size in words
+    pub sector_len : usize,
+}
+
+/// Flash memory geometry
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Geometry<W = u32> {
+    /// Sector layout in address order, sectors may differ in size
+    pub sectors      : &'static [SectorRegion],
+    /// Minimal programmable unit size in words. Every unit is programmed
+    /// only once, records and page header fields are padded to it.
+    pub program_unit : usize,
+    /// Value of erased word
+    pub erased       : W,
 }
 
 pub trait StorageMem {
+    /// Addressing unit, all offsets and sizes are in words of this type
+    type Word : Word;
     type Error;
-    fn write(&mut self, offset_words : usize, word : Word) -> Result<(), Self::Error>;
-    fn read(&self, offset_words : usize) -> Word;
-    fn read_slice(&self, offset_start : usize, offset_end : usize) -> &'static [Word];
+    /// Program one unit, `offset_words` is aligned to `Geometry::program_unit`
+    /// and `words` is exactly one unit long
+    fn write(&mut self, offset_words : usize, words : &[Self::Word]) -> Result<(), Self::Error>;
+    fn read(&self, offset_words : usize) -> Result<Self::Word, Self::Error>;
+    /// Copy words starting at `offset_words` to `words`. Override it when
+    /// memory can be read in bursts, e.g. SPI flash.
+    fn read_into(&self, offset_words : usize, words : &mut [Self::Word]) -> Result<(), Self::Error> {
+        for (n, word) in words.iter_mut().enumerate() {
+            *word = self.read(offset_words + n)?;
+        }
+        Ok(())
+    }
+    /// Erase sector, after that every word of it reads as `Geometry::erased`
+    fn erase(&mut self, sector : usize) -> Result<(), Self::Error>;
+    fn geometry(&self) -> Geometry<Self::Word>;
+    /// Memory size in words
     fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn sectors_num(&self) -> usize {
+        self.geometry().sectors_num()
+    }
+}
+
+/// Storage memory directly addressable by CPU, records can be borrowed
+/// from it without copying
+pub trait MappedMem : StorageMem {
+    fn read_slice(&self, offset_start : usize, offset_end : usize) -> &[Self::Word];
+}
+
+impl<W> Geometry<W> {
+    pub fn sectors_num(&self) -> usize {
+        self.sectors.iter().map(|region| region.count).sum()
+    }
+
+    /// Sector size in words
+    pub fn sector_len(&self, sector : usize) -> usize {
+        let mut first = 0;
+        for region in self.sectors {
+            if sector < first + region.count {
+                return region.sector_len;
+            }
+            first += region.count;
+        }
+        panic!("Sector {} is out of bounds", sector);
+    }
+
+    /// Offset of the first sector word
+    pub fn sector_start(&self, sector : usize) -> usize {
+        let mut first = 0;
+        let mut start = 0;
+        for region in self.sectors {
+            if sector < first + region.count {
+                return start + (sector - first) * region.sector_len;
+            }
+            first += region.count;
+            start += region.count * region.sector_len;
+        }
+        panic!("Sector {} is out of bounds", sector);
+    }
+
+    /// Sector that holds word at offset
+    pub fn sector_at(&self, offset : usize) -> usize {
+        let mut first = 0;
+        let mut start = 0;
+        for region in self.sectors {
+            let region_len = region.count * region.sector_len;
+            if offset < start + region_len {
+                return first + (offset - start) / region.sector_len;
+            }
+            first += region.count;
+            start += region_len;
+        }
+        panic!("Offset {} is out of bounds", offset);
+    }
 }
 
-pub trait StorageHasher32 {
+pub trait StorageHasher32<W = u32> {
     fn reset(&mut self);
-    fn write(&mut self, words: &[u32]);
+    fn write(&mut self, words: &[W]);
     fn sum(&self) -> u32;
 }
 
-pub struct Storage<S> {
+/// Storage memory access of record log. Futures of sync storage memory
+/// complete at the first poll.
+trait Mem {
+    type Word : Word;
+    type Error;
+    async fn mem_write(&mut self, offset_words : usize, words : &[Self::Word]) -> Result<(), Self::Error>;
+    async fn mem_read_into(&self, offset_words : usize, words : &mut [Self::Word]) -> Result<(), Self::Error>;
+    async fn mem_erase(&mut self, sector : usize) -> Result<(), Self::Error>;
+    fn mem_geometry(&self) -> Geometry<Self::Word>;
+    fn mem_len(&self) -> usize;
+}
+
+impl<S : StorageMem> Mem for S {
+    type Word = S::Word;
+    type Error = S::Error;
+
+    async fn mem_write(&mut self, offset_words : usize, words : &[S::Word]) -> Result<(), S::Error> {
+        self.write(offset_words, words)
+    }
+
+    async fn mem_read_into(&self, offset_words : usize, words : &mut [S::Word]) -> Result<(), S::Error> {
+        self.read_into(offset_words, words)
+    }
+
+    async fn mem_erase(&mut self, sector : usize) -> Result<(), S::Error> {
+        self.erase(sector)
+    }
+
+    fn mem_geometry(&self) -> Geometry<S::Word> {
+        self.geometry()
+    }
+
+    fn mem_len(&self) -> usize {
+        self.len()
+    }
+}
+
+/// Run future of sync storage memory
+fn ready<T>(future : impl Future<Output = T>) -> T {
+    let mut future = pin!(future);
+    match future.as_mut().poll(&mut Context::from_waker(Waker::noop())) {
+        Poll::Ready(value) => value,
+        Poll::Pending => unreachable!("Sync storage memory never waits"),
+    }
+}
+
+/// Point table entry of record found by `init` to it. Tag may be dropped by
+/// newer firmware, such records are left for the caller to migrate.
+fn apply_record(list : &mut (impl RecordIndex + ?Sized), stats : &mut InitStats, on_unknown : &mut impl FnMut(RecordDesc), found : RecordDesc) {
+    match list.find_mut(found.tag) {
+        Some(record) => record.offset = found.offset,
+        None => {
+            stats.unknown_records += 1;
+            on_unknown(found);
+        }
+    }
+}
+
+/// Append only record log.
+///
+/// Storage memory is split into pages, one page per flash sector, so pages may
+/// differ in size. Pages form a ring: records are appended to the head page,
+/// when it is full the next erased page is opened. Erased pages able to take
+/// content of the largest page are kept in reserve for `compact`, which
+/// reclaims the oldest page by copying records still living there to the head.
+///
+/// Record header layout is chosen by `F`, see `CompactHeader` for small records.
+/// See `AsyncStorage` for storage memory with async operations.
+pub struct Storage<S, F = FullHeader> {
     storage : S,
     current : usize,
+    page    : usize,
+    verify  : bool,
+    // Compaction drops records of tags missing in the table
+    drop_unknown : bool,
+    // Head page is known, so records may be appended
+    initialized : bool,
+    // Updates which payload was already stored
+    skipped_writes : usize,
+    format  : PhantomData<F>,
 }
 
 impl<S : StorageMem> Storage<S> {
 
     pub fn new(storage : S) -> Self {
+        Self::with_format(storage)
+    }
+}
+
+impl<S, F : HeaderFormat> Storage<S, F> {
+
+    /// Storage with records of given header format
+    pub fn with_format(storage : S) -> Self {
         Self {
             storage,
             current : 0,
+            page    : 0,
+            verify  : false,
+            drop_unknown : false,
+            initialized : false,
+            skipped_writes : 0,
+            format  : PhantomData,
         }
     }
-    
-    /// Scan through storage memory and populate record descriptor table
-    pub fn init(&mut self, list : &mut [RecordDesc], hasher : &mut impl StorageHasher32) -> InitStats {
 
-        let mut stats = InitStats { words_wasted : 0, unique_tags : 0 };
-        
-        let mut idx = 0;
-        let mut size = 0;
-        let mut last_valid_end = 0;
-        let capacity = self.storage.len();
-        
-        // Scanning through whole storage to find all valid records
-        while idx < capacity - HEADER_LEN {
-            let res = self.validate_record(idx, hasher);
-            match res {
-                Some(header) => {
-                    assert_eq!(list[header.tag as usize].tag, header.tag, "Index in table should match tag!");
-                    list[header.tag as usize].ptr = Some(header);
-                    idx += HEADER_LEN + header.sz as usize;
-                    last_valid_end = idx;
-                }
-                None => {
-                    idx += 1;
+    /// Read back every written record and compare it with the source. Record
+    /// that doesn't match is discarded and written again further in the log.
+    pub fn set_verify(&mut self, verify : bool) {
+        self.verify = verify;
+    }
+
+    /// Let compaction drop records of tags missing in the table, e.g. tags
+    /// retired by newer firmware. Off by default, so storage shared by
+    /// components may be compacted with the table of any of them.
+    pub fn set_drop_unknown(&mut self, drop_unknown : bool) {
+        self.drop_unknown = drop_unknown;
+    }
+
+    /// Number of updates skipped since storage was created, because record
+    /// already had the same payload
+    pub fn skipped_writes(&self) -> usize {
+        self.skipped_writes
+    }
+}
+
+impl<S : StorageMem, F : HeaderFormat> Storage<S, F> {
+
+    /// Scan through storage memory and populate record descriptor table.
+    /// Storage can't be written before it.
+    pub fn init(&mut self, list : &mut (impl RecordIndex + ?Sized), hasher : &mut impl StorageHasher32<S::Word>) -> Result<InitStats,Error<S::Error>> {
+        self.init_with(list, hasher, |_| {})
+    }
+
+    /// Same as `init`, descriptor of every record which tag isn't in the
+    /// table is given to `on_unknown`, oldest first, tombstone has no
+    /// offset. Such records are kept by compaction unless
+    /// `set_drop_unknown` is on.
+    pub fn init_with(&mut self, list : &mut (impl RecordIndex + ?Sized), hasher : &mut impl StorageHasher32<S::Word>, mut on_unknown : impl FnMut(RecordDesc)) -> Result<InitStats,Error<S::Error>> {
+        ready(self.init_async(list, hasher, &mut on_unknown))
+    }
+
+    /// Update recordy entry
+    ///
+    /// Returns `Error::OutOfFreeSpace` when there is no room for the record
+    /// left, in that case `compact` storage and try again.
+    ///
+    /// Payload length is kept in bytes, `get` returns exactly the same slice.
+    pub fn update(&mut self, record : &mut RecordDesc, payload : &[u8], hasher : &mut impl StorageHasher32<S::Word>) -> Result<(),Error<S::Error>> {
+        ready(self.update_async(record, payload, hasher))
+    }
+
+    /// Update record with given tag in `list`, reclaiming oldest pages while
+    /// storage is full
+    pub fn update_or_compact(&mut self, list : &mut (impl RecordIndex + ?Sized), tag : u32, payload : &[u8], hasher : &mut impl StorageHasher32<S::Word>) -> Result<(),Error<S::Error>> {
+        ready(self.update_or_compact_async(list, tag, payload, hasher))
+    }
+
+    /// Remove record: append tombstone, so `init` finds the record missing.
+    /// Record which isn't stored takes no space.
+    pub fn remove(&mut self, record : &mut RecordDesc, hasher : &mut impl StorageHasher32<S::Word>) -> Result<(),Error<S::Error>> {
+        ready(self.remove_async(record, hasher))
+    }
+
+    /// Remove record with given tag in `list`, reclaiming oldest pages while
+    /// storage is full
+    pub fn remove_or_compact(&mut self, list : &mut (impl RecordIndex + ?Sized), tag : u32, hasher : &mut impl StorageHasher32<S::Word>) -> Result<(),Error<S::Error>> {
+        ready(self.remove_or_compact_async(list, tag, hasher))
+    }
+
+    /// Reclaim the oldest page: copy latest versions of records still living
+    /// there to the head page and erase it.
+    ///
+    /// Reset at any point of compaction is handled by `init`: until the page
+    /// is marked obsolete its copies are just newer duplicates of records.
+    ///
+    /// Records of tags missing in `list` are copied too, each of them costs
+    /// a walk over the rest of the log to find whether it is the latest.
+    pub fn compact(&mut self, list : &mut (impl RecordIndex + ?Sized), hasher : &mut impl StorageHasher32<S::Word>) -> Result<(),Error<S::Error>> {
+        ready(self.compact_async(list, hasher))
+    }
+
+    /// Copy record payload to `buf`, returns payload size in bytes. Works
+    /// with any storage memory, see `get` for memory mapped one.
+    pub fn get_into(&self, record : &RecordDesc, buf : &mut [u8]) -> Result<Option<usize>,Error<S::Error>> {
+        ready(self.get_into_async(record, buf))
+    }
+
+    /// Total amount of occupied storage space in bytes
+    pub fn len(&self) -> Result<usize,Error<S::Error>> {
+        ready(self.len_async())
+    }
+    pub fn is_empty(&self) -> Result<bool,Error<S::Error>> {
+        Ok(self.len()? == 0)
+    }
+    /// Total storage space in bytes, retired sectors excluded
+    pub fn capacity(&self) -> Result<usize,Error<S::Error>> {
+        ready(self.capacity_async())
+    }
+}
+
+// Engine is shared by sync and async storage, `Mem` stays internal
+#[allow(private_bounds)]
+impl<S : Mem, F : HeaderFormat> Storage<S, F> {
+
+    pub(crate) async fn init_async(&mut self, list : &mut (impl RecordIndex + ?Sized), hasher : &mut impl StorageHasher32<S::Word>, on_unknown : &mut impl FnMut(RecordDesc)) -> Result<InitStats,Error<S::Error>> {
+
+        let mut stats = InitStats { words_wasted : 0, unique_tags : 0, torn_records : 0, corrupted_records : 0, discarded_records : 0, unknown_records : 0, retired_sectors : 0, retired_words : 0, skipped_writes : self.skipped_writes };
+
+        if !list.is_consistent() {
+            return Err(Error::InconsistentTable);
+        }
+        // Records of such tags could never be written
+        if list.records().iter().any(|record| record.tag > F::MAX_TAG) {
+            return Err(Error::TagOutOfRange);
+        }
+
+        let geometry = self.storage.mem_geometry();
+        assert!((1 ..= MAX_PROGRAM_UNIT_SIZE).contains(&(geometry.program_unit * size_of::<S::Word>())), "Unsupported program unit");
+        assert!(![PAGE_STATE_SET, RECORD_COMMITTED, RECORD_DISCARDED, RECORD_REMOVED, RECORD_STAGED, RECORD_TX_COMMIT].contains(&self.erased_field()), "Erased value clashes with state marker");
+
+        self.initialized = false;
+        let oldest = self.recover_pages().await?;
+
+        for e in list.records_mut() {
+            e.offset = None;
+        }
+
+        // Staged records since the last record of other kind: position of
+        // the first one and their number
+        let mut run : Option<(LogPos, usize)> = None;
+
+        // Pages are scanned from the oldest one, so newer versions of
+        // a record override older ones. Last scanned page is the head.
+        let pages_num = self.pages_num();
+        for (n, page) in (0 .. pages_num).map(|n| (n, (oldest + n) % pages_num)) {
+            if self.page_state(page).await? != PageState::Active {
+                continue;
+            }
+
+            let page_end = self.page_end(page);
+            let mut idx = self.data_start(page);
+            let mut last_valid_end = idx;
+
+            // Scanning through whole page to find all valid records
+            while idx + self.record_len(0) <= page_end {
+                match self.scan_record(idx, page_end, hasher).await? {
+                    RecordState::Committed(tag, len) => {
+                        run = None;
+                        apply_record(list, &mut stats, on_unknown, RecordDesc { tag, offset : Some(idx) });
+                        idx += len;
+                        last_valid_end = idx;
+                    }
+                    RecordState::Removed(tag, len) => {
+                        run = None;
+                        apply_record(list, &mut stats, on_unknown, RecordDesc { tag, offset : None });
+                        idx += len;
+                        last_valid_end = idx;
+                    }
+                    RecordState::Staged(_, len) => {
+                        run.get_or_insert(((n, idx), 0)).1 += 1;
+                        idx += len;
+                        last_valid_end = idx;
+                    }
+                    RecordState::TxCommit(count, len) => {
+                        // Records staged before the last `count` ones belong
+                        // to transactions which were never committed
+                        if let Some((start, staged)) = run.take() {
+                            let skip = staged.saturating_sub(count as usize);
+                            self.apply_staged(list, &mut stats, on_unknown, oldest, start, skip, (n, idx), hasher).await?;
+                        }
+                        idx += len;
+                        last_valid_end = idx;
+                    }
+                    RecordState::Torn(len) => {
+                        stats.torn_records += 1;
+                        idx += len;
+                        last_valid_end = idx;
+                    }
+                    RecordState::Corrupted(len) => {
+                        stats.corrupted_records += 1;
+                        idx += len;
+                        last_valid_end = idx;
+                    }
+                    RecordState::Discarded(len) => {
+                        stats.discarded_records += 1;
+                        idx += len;
+                        last_valid_end = idx;
+                    }
+                    // Space of record which write has failed may be left
+                    // erased, so look for records after it as well
+                    RecordState::Erased | RecordState::Garbage => {
+                        idx = self.resync(idx + self.unit(), page_end).await?;
+                    }
                 }
             }
-        }
-        
-        // Scannig from last record end position, to determine that
-        // rest flash memory wasn't already written (NOT 0xFF'ed)
-        for idx in last_valid_end .. capacity {
-            if !Self::is_ffed(self.storage.read(idx)) {
-                size = idx + 1;
-                stats.words_wasted += 1;
+
+            // Scannig from last record end position, to determine that
+            // rest flash memory wasn't already written (NOT erased)
+            let mut size = last_valid_end;
+            for idx in last_valid_end .. page_end {
+                if !self.is_erased(self.read_word(idx).await?) {
+                    size = self.align(idx + 1);
+                    stats.words_wasted += 1;
+                }
             }
-        }
 
-        self.current = size;
+            self.page = page;
+            self.current = size;
+        }
 
         // Stats
-        for e in list {
-            if let Some(_) = &e.ptr {
+        for page in 0 .. pages_num {
+            if self.is_page_retired(page).await? {
+                stats.retired_sectors += 1;
+                stats.retired_words += self.page_end(page) - self.page_start(page);
+            }
+        }
+        for e in list.records() {
+            if e.offset.is_some() {
                 stats.unique_tags += 1;
             }
         }
-        
-        stats
-    }
 
-    fn validate_record(&self, idx : usize, hasher : &mut impl StorageHasher32) -> Option<&'static Header> {
-        let _tag = self.storage.read(idx);
-        let len = self.storage.read(idx + 1);
-        let crc = self.storage.read(idx + 2);
+        self.initialized = true;
+        Ok(stats)
+    }
 
-        let payload_start_idx = idx + 3;
-        let payload_end_idx = payload_start_idx.saturating_add(len as usize);
-        // Check payload slice is not out of bounds
-        if payload_end_idx > self.storage.len() {
-            return None;
+    async fn scan_record(&self, idx : usize, page_end : usize, hasher : &mut impl StorageHasher32<S::Word>) -> Result<RecordState,Error<S::Error>> {
+        // Sync field is programmed last of header fields
+        if self.is_range_erased(idx, idx + Self::words_len(FIELD_SIZE)).await? {
+            if self.is_range_erased(idx, idx + Self::words_len(F::LEN * FIELD_SIZE)).await? {
+                return Ok(RecordState::Erased);
+            }
+            return Ok(RecordState::Torn(self.header_len()));
         }
-        
-        // Calculate checksum
-        hasher.reset();
-        let header_part = self.storage.read_slice(idx, idx + 2);
-        hasher.write(header_part);
-        let payload_slice = self.storage.read_slice(payload_start_idx, payload_end_idx);
-        hasher.write(payload_slice);
-        
-        // Compare checksums
-        let calc_crc = hasher.sum();
-        if crc != calc_crc {
-            return None;
+        let words = self.read_header(idx).await?;
+        if words[0] & F::SYNC_MASK != F::SYNC {
+            return Ok(RecordState::Garbage);
         }
-        
-        let header : &Header = unsafe { &*(self.storage.read_slice(idx, idx).as_ptr() as *const _) };
-        Some(header)
-    }
-    
-    /// Update recordy entry
-    pub fn update(&mut self, record : &mut RecordDesc, payload : &[Word], hasher : &mut impl StorageHasher32) -> Result<(),Error> {
-        let record_len = HEADER_LEN + payload.len();
-        if self.free_space_in_words() < record_len {
-            return Err(Error::OutOfFreeSpace);
+        let fields = F::decode(&words);
+        let (tag, len) = (fields.tag, fields.sz);
+        if F::encode(&fields, Self::header_check(tag, len, hasher))[.. F::LEN] != words[.. F::LEN] {
+            return Ok(RecordState::Garbage);
         }
 
-        let header_idx = self.current;
-        // Fill header
-        assert!(self.storage.write(header_idx + 0, record.tag).is_ok());
-        assert!(self.storage.write(header_idx + 1, payload.len() as Word).is_ok());
+        // Check payload slice and commit field are not out of page bounds
+        if (len as usize) > page_end * size_of::<S::Word>() || idx + self.record_len(len as usize) > page_end {
+            return Ok(RecordState::Garbage);
+        }
+        let record_len = self.record_len(len as usize);
+        let payload_start_idx = idx + self.header_len();
 
-        let payload_idx = header_idx + HEADER_LEN;
-        // Copy payload
-        for idx in 0 .. payload.len() {
-            assert!(self.storage.write(payload_idx + idx, payload[idx]).is_ok());
+        let commit = self.read_field(idx + record_len - self.commit_len()).await?;
+        match commit {
+            RECORD_COMMITTED | RECORD_REMOVED | RECORD_STAGED | RECORD_TX_COMMIT => {}
+            RECORD_DISCARDED => return Ok(RecordState::Discarded(record_len)),
+            _ => return Ok(RecordState::Torn(record_len)),
         }
-        
-        // Calculate and set checksum
-        hasher.reset();
-        hasher.write(self.storage.read_slice(header_idx, header_idx + 2));
-        hasher.write(self.storage.read_slice(payload_idx, payload_idx + payload.len()));
-        let checksum = hasher.sum();
-        assert!(self.storage.write(header_idx + 2, checksum).is_ok());
 
-        // Update record descriptor
-        let updated_header : &Header = unsafe { &*(self.storage.read_slice(header_idx, header_idx).as_ptr() as *const Header) };
-        record.ptr = Some(updated_header);
+        // Compare checksums
+        if fields.crc != self.payload_crc(payload_start_idx, tag, len, hasher).await? {
+            return Ok(RecordState::Corrupted(record_len));
+        }
 
-        // Update current len
-        self.current += record_len;
+        Ok(match commit {
+            RECORD_REMOVED => RecordState::Removed(tag, record_len),
+            RECORD_STAGED => RecordState::Staged(tag, record_len),
+            RECORD_TX_COMMIT => RecordState::TxCommit(tag, record_len),
+            _ => RecordState::Committed(tag, record_len),
+        })
+    }
 
-        Ok(())
+    /// Checksum of stored payload, payload is streamed to hasher by chunks
+    async fn payload_crc(&self, payload_idx : usize, tag : u32, len : u32, hasher : &mut impl StorageHasher32<S::Word>) -> Result<u32,Error<S::Error>> {
+        hasher.reset();
+        Self::hash_fields(&[tag, len], hasher);
+        self.read_chunks(payload_idx, Self::words_len(len as usize), |chunk| hasher.write(chunk)).await?;
+        Ok(hasher.sum() & F::CRC_MASK)
     }
-    
-    /// Get record payload
-    pub fn get(&self, record : &RecordDesc) -> Result<Option<&'static [u32]>,Error> {
-        match record.ptr {
-            Some(header) => {
-                // Basic sanity check
-                if header.tag == record.tag {
-                    unsafe {
-                        let header_ptr = header as *const _ as *const u32;
-                        let payload_ptr = header_ptr.offset(HEADER_LEN as isize);
-                        Ok(Some(from_raw_parts(payload_ptr, header.sz as usize)))
-                    }
-                } else {
-                    Err(Error::CorruptedRecordOnGet)
-                }
-            },
-            None => Ok(None),
+
+    /// Pass `len` words starting at `idx` to `f` by chunks
+    async fn read_chunks(&self, idx : usize, len : usize, mut f : impl FnMut(&[S::Word])) -> Result<(),Error<S::Error>> {
+        let mut buf = [S::Word::default(); READ_CHUNK];
+        for start in (idx .. idx + len).step_by(READ_CHUNK) {
+            let chunk = &mut buf[.. READ_CHUNK.min(idx + len - start)];
+            self.storage.mem_read_into(start, chunk).await.map_err(Error::Mem)?;
+            f(chunk);
         }
+        Ok(())
     }
 
-    /// Total amount of occupied storage space in bytes
-    pub fn len(&self) -> usize {
-        self.current * WORD_SIZE
+    async fn read_word(&self, idx : usize) -> Result<S::Word,Error<S::Error>> {
+        let mut word = [S::Word::default()];
+        self.storage.mem_read_into(idx, &mut word).await.map_err(Error::Mem)?;
+        Ok(word[0])
     }
-    /// Total storage space in bytes
-    pub fn capacity(&self) -> usize {
-        self.storage.len() * WORD_SIZE
+
+    async fn is_range_erased(&self, start : usize, end : usize) -> Result<bool,Error<S::Error>> {
+        let mut erased = true;
+        self.read_chunks(start, end - start, |chunk| erased &= chunk.iter().all(|&word| self.is_erased(word))).await?;
+        Ok(erased)
     }
 
-    fn free_space_in_words(&self) -> usize {
-        self.storage.len() - self.current
+    /// Header check of record with given tag and payload size
+    fn header_check(tag : u32, len : u32, hasher : &mut impl StorageHasher32<S::Word>) -> u32 {
+        hasher.reset();
+        Self::hash_fields(&[tag, len], hasher);
+        hasher.sum()
     }
 
-    fn is_ffed(word : Word) -> bool {
-        if word == !0 {
-            return true;
+    /// Feed fields to hasher as words
+    fn hash_fields(fields : &[u32], hasher : &mut impl StorageHasher32<S::Word>) {
+        let mut bytes = [0; MAX_HEADER_LEN * FIELD_SIZE];
+        for (chunk, field) in bytes.chunks_mut(FIELD_SIZE).zip(fields) {
+            chunk.copy_from_slice(&field.to_ne_bytes());
+        }
+        for word in Self::payload_words(&bytes[.. fields.len() * FIELD_SIZE]) {
+            hasher.write(&[word]);
         }
-        return false;
     }
 
-    fn header_from_slice(&self, _slice : &'static [u32]) -> &'static Header {
-        todo!()
+    /// Find next program unit that looks like record start
+    async fn resync(&self, mut idx : usize, page_end : usize) -> Result<usize,Error<S::Error>> {
+        while idx + Self::words_len(FIELD_SIZE) <= page_end && self.read_field(idx).await? & F::SYNC_MASK != F::SYNC {
+            idx += self.unit();
+        }
+        Ok(idx)
     }
 
-    fn payload_from_header_slice(&self, _header : &'static Header) -> Result<&'static [u32],()> {
-        todo!()
+    /// Record len in words for payload of given number of bytes, every
+    /// record part is padded to program unit
+    fn record_len(&self, payload_len : usize) -> usize {
+        self.header_len() + self.align(Self::words_len(payload_len)) + self.commit_len()
     }
-}
 
-#[cfg(any(test, feature="test-def"))]
-pub use test_def::TestMem;
+    /// Record header len in words
+    fn header_len(&self) -> usize {
+        self.align(Self::words_len(F::LEN * FIELD_SIZE))
+    }
 
-#[cfg(any(test, feature="test-def"))]
-mod test_def {
-    use super::*;
+    /// Commit field len in words
+    fn commit_len(&self) -> usize {
+        self.align(Self::words_len(COMMIT_LEN * FIELD_SIZE))
+    }
 
-    pub use crc::crc32::{Digest, Hasher32};
+    /// Number of words taking given number of bytes
+    fn words_len(bytes : usize) -> usize {
+        bytes.div_ceil(size_of::<S::Word>())
+    }
 
-    impl StorageHasher32 for Digest {
-        fn reset(&mut self) {
-            <Digest as Hasher32>::reset(self);
-        }
+    /// Read bytes starting at word `idx`
+    async fn read_bytes(&self, idx : usize, bytes : &mut [u8]) -> Result<(),Error<S::Error>> {
+        let mut chunks = bytes.chunks_mut(size_of::<S::Word>());
+        self.read_chunks(idx, Self::words_len(chunks.len() * size_of::<S::Word>()), |words| {
+            for (word, chunk) in words.iter().zip(&mut chunks) {
+                word.to_bytes(chunk);
+            }
+        }).await
+    }
 
-        fn write(&mut self, words: &[u32]) {
-            let bytes = unsafe { 
-                from_raw_parts(words.as_ptr() as *const u8, words.len() * WORD_SIZE) 
-            };
-            <Digest as Hasher32>::write(self, bytes);
+    async fn read_field(&self, idx : usize) -> Result<u32,Error<S::Error>> {
+        let mut bytes = [0; FIELD_SIZE];
+        self.read_bytes(idx, &mut bytes).await?;
+        Ok(u32::from_ne_bytes(bytes))
+    }
+
+    /// Record header fields starting at word `idx`
+    async fn read_header(&self, idx : usize) -> Result<[u32; MAX_HEADER_LEN],Error<S::Error>> {
+        let mut bytes = [0; MAX_HEADER_LEN * FIELD_SIZE];
+        self.read_bytes(idx, &mut bytes[.. F::LEN * FIELD_SIZE]).await?;
+        Ok(Self::bytes_to_fields(&bytes))
+    }
+
+    fn bytes_to_fields(bytes : &[u8; MAX_HEADER_LEN * FIELD_SIZE]) -> [u32; MAX_HEADER_LEN] {
+        let mut fields = [0; MAX_HEADER_LEN];
+        for (field, chunk) in fields.iter_mut().zip(bytes.chunks(FIELD_SIZE)) {
+            *field = u32::from_ne_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
         }
+        fields
+    }
 
-        fn sum(&self) -> u32 {
-            <Digest as Hasher32>::sum32(self)
+    fn fields_to_bytes(fields : &[u32; MAX_HEADER_LEN]) -> [u8; MAX_HEADER_LEN * FIELD_SIZE] {
+        let mut bytes = [0; MAX_HEADER_LEN * FIELD_SIZE];
+        for (chunk, field) in bytes.chunks_mut(FIELD_SIZE).zip(fields) {
+            chunk.copy_from_slice(&field.to_ne_bytes());
         }
+        bytes
     }
 
-    pub struct TestMem ( pub [Word;0x100] );
+    fn unit(&self) -> usize {
+        self.storage.mem_geometry().program_unit
+    }
 
-    impl StorageMem for TestMem {
-        type Error = ();
+    /// Round len up to program unit
+    fn align(&self, len : usize) -> usize {
+        len.div_ceil(self.unit()) * self.unit()
+    }
 
-        fn write(&mut self, offset_words : usize, word : Word) -> Result<(), Self::Error> {
-            Ok(self.0[offset_words] = word)
-        }
+    /// Program field starting at unit aligned `idx`, padded to program unit
+    async fn program_field(&mut self, idx : usize, field : u32) -> Result<(),Error<S::Error>> {
+        self.program_bytes(idx, &field.to_ne_bytes()).await
+    }
 
-        fn read(&self, offset_words : usize) -> Word {
-            self.0[offset_words]
+    /// Program bytes starting at unit aligned `idx`, last word and unit are
+    /// padded with zeroes
+    async fn program_bytes(&mut self, idx : usize, bytes : &[u8]) -> Result<(),Error<S::Error>> {
+        let unit = self.unit();
+        for (n, chunk) in bytes.chunks(unit * size_of::<S::Word>()).enumerate() {
+            // Unit is never longer in words than in bytes
+            let mut buf = [S::Word::default(); MAX_PROGRAM_UNIT_SIZE];
+            for (word, src) in buf.iter_mut().zip(Self::payload_words(chunk)) {
+                *word = src;
+            }
+            self.storage.mem_write(idx + n * unit, &buf[.. unit]).await.map_err(Error::Mem)?;
         }
+        Ok(())
+    }
 
-        fn read_slice(&self, offset_start : usize, offset_end : usize) -> &'static [Word] {
-            unsafe { core::mem::transmute(&self.0[offset_start .. offset_end]) }
-        }
+    /// Payload split into words, last word is padded with zeroes
+    fn payload_words(payload : &[u8]) -> impl Iterator<Item = S::Word> + '_ {
+        payload.chunks(size_of::<S::Word>()).map(S::Word::from_bytes)
+    }
 
-        fn len(&self) -> usize {
-            self.0.len()
-        }
+    pub(crate) async fn update_async(&mut self, record : &mut RecordDesc, payload : &[u8], hasher : &mut impl StorageHasher32<S::Word>) -> Result<(),Error<S::Error>> {
+        self.put(record, payload, RECORD_COMMITTED, hasher).await
     }
-}
 
+    pub(crate) async fn remove_async(&mut self, record : &mut RecordDesc, hasher : &mut impl StorageHasher32<S::Word>) -> Result<(),Error<S::Error>> {
+        self.put(record, &[], RECORD_REMOVED, hasher).await
+    }
 
-#[allow(dead_code, unused_imports)]
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crc::crc32::{Digest, IEEE_TABLE, IEEE, Hasher32};
-    use crc::CalcType;
-    
-    fn crc32_ethernet() -> impl StorageHasher32 {
-        Digest::new_custom(IEEE, !0u32, 0u32, CalcType::Normal)
+    pub(crate) async fn update_or_compact_async(&mut self, list : &mut (impl RecordIndex + ?Sized), tag : u32, payload : &[u8], hasher : &mut impl StorageHasher32<S::Word>) -> Result<(),Error<S::Error>> {
+        self.put_or_compact(list, tag, payload, RECORD_COMMITTED, hasher).await
     }
 
-    fn new_storage() -> Storage<TestMem> {
-        Storage::new(TestMem([!0;0x100]))
+    pub(crate) async fn remove_or_compact_async(&mut self, list : &mut (impl RecordIndex + ?Sized), tag : u32, hasher : &mut impl StorageHasher32<S::Word>) -> Result<(),Error<S::Error>> {
+        self.put_or_compact(list, tag, &[], RECORD_REMOVED, hasher).await
     }
 
-    #[test]
-    fn empty_test() {
-        let storage_mem = [!0u32;0x100];
-        let capacity = storage_mem.len() * size_of::<Word>();
-        let storage = Storage::new(TestMem(storage_mem));
+    /// Append record with given commit field value
+    async fn put(&mut self, record : &mut RecordDesc, payload : &[u8], commit : u32, hasher : &mut impl StorageHasher32<S::Word>) -> Result<(),Error<S::Error>> {
+        self.check_initialized()?;
+        // Record which isn't stored needs no tombstone
+        if commit == RECORD_REMOVED && record.offset.is_none() {
+            return Ok(());
+        }
+        // Rewriting the same payload only wears flash
+        if commit == RECORD_COMMITTED && self.is_stored(record, payload).await? {
+            self.skipped_writes += 1;
+            return Ok(());
+        }
+        self.append(record, Payload::Bytes(payload), commit, hasher, false).await?;
+        Ok(())
+    }
 
-        assert_eq!(storage.len(), 0);
-        assert_eq!(storage.capacity(), capacity);
+    async fn put_or_compact(&mut self, list : &mut (impl RecordIndex + ?Sized), tag : u32, payload : &[u8], commit : u32, hasher : &mut impl StorageHasher32<S::Word>) -> Result<(),Error<S::Error>> {
+        let idx = list.position(tag).expect("Tag isn't in record table");
+        self.write_or_compact(list, hasher, async |log, list, hasher| log.put(&mut list.records_mut()[idx], payload, commit, hasher).await).await
     }
-    
-    #[test]
+
+    /// Run `write`, reclaiming oldest pages while storage is full
+    pub(crate) async fn write_or_compact<L : RecordIndex + ?Sized, H : StorageHasher32<S::Word>>(&mut self, list : &mut L, hasher : &mut H, mut write : impl AsyncFnMut(&mut Self, &mut L, &mut H) -> Result<(),Error<S::Error>>) -> Result<(),Error<S::Error>> {
+        // Once every page is reclaimed there is nothing left to free
+        for _ in 0 .. self.pages_num() {
+            match write(self, list, hasher).await {
+                Err(Error::OutOfFreeSpace) => self.compact_async(list, hasher).await?,
+                res => return res,
+            }
+        }
+        write(self, list, hasher).await
+    }
+
+    /// Append record, returns its position. Only committed record is pointed
+    /// to by `record`, tombstone leaves it `None`.
+    async fn append(&mut self, record : &mut RecordDesc, payload : Payload<'_>, commit : u32, hasher : &mut impl StorageHasher32<S::Word>, use_reserve : bool) -> Result<usize,Error<S::Error>> {
+        self.check_initialized()?;
+        let payload_len = self.payload_len(payload).await?;
+        let record_len = self.record_len(payload_len);
+        self.check_record_size(payload_len).await?;
+        if record.tag > F::MAX_TAG {
+            return Err(Error::TagOutOfRange);
+        }
+
+        // Head page is retired after `WRITE_ATTEMPTS` failures in a row and
+        // record is written again in the next page
+        let mut failures = 0;
+        for _ in 0 .. WRITE_ATTEMPTS * self.pages_num() {
+            if failures == WRITE_ATTEMPTS {
+                // Page may be retired already by write which found no next page
+                if !self.is_page_retired(self.page).await? {
+                    self.set_page_state(self.page, PAGE_BAD).await?;
+                }
+                self.open_next_page(use_reserve).await?;
+                failures = 0;
+            }
+            if self.free_space_in_words().await? < record_len {
+                if record_len > self.page_data_len(self.page) {
+                    return Err(Error::OutOfFreeSpace);
+                }
+                self.open_next_page(use_reserve).await?;
+                failures = 0;
+            }
+
+            let header_idx = self.current;
+            let commit_idx = header_idx + record_len - self.commit_len();
+            // Space of failed record is never reused, it is skipped by `init`
+            // as torn one and programming over it again is not allowed
+            self.current += record_len;
+            self.write_record(header_idx, record.tag, payload, hasher).await?;
+
+            if self.verify && !self.verify_record(header_idx, record.tag, payload, hasher).await? {
+                self.program_field(commit_idx, RECORD_DISCARDED).await?;
+                failures += 1;
+                continue;
+            }
+
+            self.program_field(commit_idx, commit).await?;
+            // Commit field failed is the same as torn record
+            if self.verify && self.read_field(commit_idx).await? != commit {
+                failures += 1;
+                continue;
+            }
+
+            match commit {
+                RECORD_COMMITTED => record.offset = Some(header_idx),
+                RECORD_REMOVED => record.offset = None,
+                _ => {}
+            }
+
+            return Ok(header_idx);
+        }
+
+        Err(Error::WriteVerifyFailed)
+    }
+
+    /// Write position is unknown until `init`, record written at the start
+    /// of memory would clobber page header
+    fn check_initialized(&self) -> Result<(),Error<S::Error>> {
+        if !self.initialized {
+            return Err(Error::NotInitialized);
+        }
+        Ok(())
+    }
+
+    /// Payload of given size fits into record header and record fits into
+    /// the largest page still in use
+    async fn check_record_size(&self, payload_len : usize) -> Result<(),Error<S::Error>> {
+        let mut max_len = 0;
+        for page in 0 .. self.pages_num() {
+            if !self.is_page_retired(page).await? {
+                max_len = max_len.max(self.page_data_len(page));
+            }
+        }
+        if payload_len > F::MAX_SZ as usize || self.record_len(payload_len) > max_len {
+            return Err(Error::RecordTooLarge);
+        }
+        Ok(())
+    }
+
+    /// Record points to stored payload equal to `payload`
+    async fn is_stored(&self, record : &RecordDesc, payload : &[u8]) -> Result<bool,Error<S::Error>> {
+        let header_idx = match record.offset {
+            Some(header_idx) => header_idx,
+            None => return Ok(false),
+        };
+        let fields = self.header_fields(header_idx).await?;
+        Ok(fields.tag == record.tag && fields.sz as usize == payload.len() && self.payload_eq(header_idx + self.header_len(), payload).await?)
+    }
+
+    /// Stored payload starting at `payload_idx` is equal to `payload`
+    async fn payload_eq(&self, payload_idx : usize, payload : &[u8]) -> Result<bool,Error<S::Error>> {
+        let mut words = Self::payload_words(payload);
+        let mut payload_eq = true;
+        self.read_chunks(payload_idx, Self::words_len(payload.len()), |chunk| {
+            payload_eq &= chunk.iter().all(|&word| words.next() == Some(word));
+        }).await?;
+        Ok(payload_eq)
+    }
+
+    /// Size of payload in bytes
+    async fn payload_len(&self, payload : Payload<'_>) -> Result<usize,Error<S::Error>> {
+        match payload {
+            Payload::Bytes(bytes) => Ok(bytes.len()),
+            Payload::Stored(idx) => Ok(self.header_fields(idx).await?.sz as usize),
+        }
+    }
+
+    /// Write record without commit word
+    async fn write_record(&mut self, header_idx : usize, tag : u32, payload : Payload<'_>, hasher : &mut impl StorageHasher32<S::Word>) -> Result<(),Error<S::Error>> {
+        let bytes = match payload {
+            Payload::Bytes(bytes) => bytes,
+            Payload::Stored(idx) => return self.copy_record(idx, header_idx).await,
+        };
+        let header = Self::fields_to_bytes(&Self::encode_header(tag, bytes, hasher));
+
+        // Header units are programmed back to front, so sync field seals it
+        let unit = self.unit();
+        let unit_bytes = unit * size_of::<S::Word>();
+        for (n, chunk) in header[.. F::LEN * FIELD_SIZE].chunks(unit_bytes).enumerate().rev() {
+            self.program_bytes(header_idx + n * unit, chunk).await?;
+        }
+
+        // Copy payload
+        self.program_bytes(header_idx + self.header_len(), bytes).await
+    }
+
+    /// Copy record at `src_idx` without commit word, header units are
+    /// programmed in the same order as by `write_record`
+    async fn copy_record(&mut self, src_idx : usize, header_idx : usize) -> Result<(),Error<S::Error>> {
+        let unit = self.unit();
+        let header_units = (0 .. self.header_len()).step_by(unit).rev();
+        let payload_len = self.align(Self::words_len(self.payload_len(Payload::Stored(src_idx)).await?));
+        let payload_units = (self.header_len() .. self.header_len() + payload_len).step_by(unit);
+        for n in header_units.chain(payload_units) {
+            let mut buf = [S::Word::default(); MAX_PROGRAM_UNIT_SIZE];
+            self.storage.mem_read_into(src_idx + n, &mut buf[.. unit]).await.map_err(Error::Mem)?;
+            self.storage.mem_write(header_idx + n, &buf[.. unit]).await.map_err(Error::Mem)?;
+        }
+        Ok(())
+    }
+
+    /// Header fields of record, checksum is calculated over source, so badly
+    /// programmed words are detected later
+    fn encode_header(tag : u32, payload : &[u8], hasher : &mut impl StorageHasher32<S::Word>) -> [u32; MAX_HEADER_LEN] {
+        let len = payload.len() as u32;
+        hasher.reset();
+        Self::hash_fields(&[tag, len], hasher);
+        for word in Self::payload_words(payload) {
+            hasher.write(&[word]);
+        }
+        let fields = HeaderFields { tag, sz : len, crc : hasher.sum() & F::CRC_MASK };
+        F::encode(&fields, Self::header_check(tag, len, hasher))
+    }
+
+    /// Compare written record with its source and check its checksum
+    async fn verify_record(&self, header_idx : usize, tag : u32, payload : Payload<'_>, hasher : &mut impl StorageHasher32<S::Word>) -> Result<bool,Error<S::Error>> {
+        let payload_len = self.payload_len(payload).await?;
+        let payload_idx = header_idx + self.header_len();
+        let header = self.read_header(header_idx).await?;
+
+        let matches = match payload {
+            Payload::Bytes(bytes) => {
+                header[.. F::LEN] == Self::encode_header(tag, bytes, hasher)[.. F::LEN] && self.payload_eq(payload_idx, bytes).await?
+            }
+            Payload::Stored(src_idx) => {
+                let len = self.header_len() + Self::words_len(payload_len);
+                let mut copy_eq = true;
+                for n in 0 .. len {
+                    copy_eq &= self.read_word(header_idx + n).await? == self.read_word(src_idx + n).await?;
+                }
+                copy_eq
+            }
+        };
+
+        Ok(matches && F::decode(&header).crc == self.payload_crc(payload_idx, tag, payload_len as u32, hasher).await?)
+    }
+
+    pub(crate) async fn compact_async(&mut self, list : &mut (impl RecordIndex + ?Sized), hasher : &mut impl StorageHasher32<S::Word>) -> Result<(),Error<S::Error>> {
+        self.check_initialized()?;
+        let oldest = self.oldest_page().await?;
+        let start = (0, self.data_start(oldest));
+
+        // Check copies will fit, page being reclaimed can't take them
+        let mut live_len = 0;
+        let mut pos = start;
+        while let Some((idx, _)) = self.next_live(list, oldest, &mut pos, hasher).await? {
+            live_len += self.record_len(self.header_fields(idx).await?.sz as usize);
+        }
+        let mut free_len = 0;
+        for page in 0 .. self.pages_num() {
+            if self.page_state(page).await? == PageState::Erased {
+                free_len += self.page_data_len(page);
+            }
+        }
+        if oldest != self.page {
+            free_len += self.free_space_in_words().await?;
+        }
+        if live_len > free_len {
+            return Err(Error::OutOfFreeSpace);
+        }
+
+        if oldest == self.page {
+            self.open_next_page(true).await?;
+        }
+
+        // Oldest page stays intact until every live record is copied
+        let mut pos = start;
+        while let Some((idx, tag)) = self.next_live(list, oldest, &mut pos, hasher).await? {
+            let mut unknown = RecordDesc { tag, offset : None };
+            let record = match list.find_mut(tag) {
+                Some(record) => record,
+                None => &mut unknown,
+            };
+            self.append(record, Payload::Stored(idx), RECORD_COMMITTED, hasher, true).await?;
+        }
+
+        self.set_page_state(oldest, PAGE_OBSOLETE).await?;
+        if !self.is_page_retired(oldest).await? {
+            self.erase_page(oldest).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Next record of the oldest page at or after `pos` which compaction
+    /// keeps: the latest version of its tag, committed alone or by
+    /// transaction. Returns its position and tag.
+    async fn next_live(&self, list : &(impl RecordIndex + ?Sized), oldest : usize, pos : &mut LogPos, hasher : &mut impl StorageHasher32<S::Word>) -> Result<Option<(usize, u32)>,Error<S::Error>> {
+        while let Some((idx, state)) = self.next_valid(oldest, pos, hasher).await? {
+            if self.header_page(idx) != oldest {
+                break;
+            }
+            let tag = match state {
+                RecordState::Committed(tag, _) => tag,
+                RecordState::Staged(tag, _) if self.is_committed(oldest, *pos, hasher).await? => tag,
+                _ => continue,
+            };
+            let live = match list.find(tag) {
+                Some(record) => record.offset == Some(idx),
+                None => !self.drop_unknown && self.is_latest(oldest, *pos, tag, hasher).await?,
+            };
+            if live {
+                return Ok(Some((idx, tag)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Bring pages to consistent state after reset and return the oldest
+    /// active page
+    async fn recover_pages(&mut self) -> Result<usize,Error<S::Error>> {
+        let mut has_active = false;
+        for page in 0 .. self.pages_num() {
+            match self.page_state(page).await? {
+                // Content of obsolete page is already copied, finish its erase
+                PageState::Obsolete => self.erase_page(page).await?,
+                PageState::Corrupted => self.erase_page(page).await?,
+                // Page is activated before any record is written to it,
+                // so there is nothing to lose
+                PageState::Receiving => self.erase_page(page).await?,
+                // Leftovers of interrupted erase
+                PageState::Erased if !self.is_page_blank(page).await? => self.erase_page(page).await?,
+                PageState::Active => has_active = true,
+                _ => {}
+            }
+        }
+
+        if !has_active {
+            // Fresh storage
+            let mut first = None;
+            for page in 0 .. self.pages_num() {
+                if self.page_state(page).await? == PageState::Erased {
+                    first = Some(page);
+                    break;
+                }
+            }
+            self.open_page(first.ok_or(Error::OutOfFreeSpace)?, 0).await?;
+        }
+
+        self.oldest_page().await
+    }
+
+    async fn page_state(&self, page : usize) -> Result<PageState,Error<S::Error>> {
+        let mut fields = [0; PAGE_HEADER_LEN];
+        for (state, field) in fields.iter_mut().enumerate() {
+            *field = self.read_field(self.page_field(page, state)).await?;
+        }
+        let is_set = |state : usize| fields[state] == PAGE_STATE_SET;
+
+        Ok(if is_set(PAGE_BAD) && (is_set(PAGE_OBSOLETE) || !is_set(PAGE_ACTIVE)) {
+            PageState::Bad
+        } else if is_set(PAGE_OBSOLETE) {
+            PageState::Obsolete
+        } else if is_set(PAGE_ACTIVE) {
+            PageState::Active
+        } else if is_set(PAGE_RECEIVING) {
+            PageState::Receiving
+        } else if self.is_range_erased(self.page_start(page), self.data_start(page)).await? {
+            PageState::Erased
+        } else {
+            PageState::Corrupted
+        })
+    }
+
+    /// Page is worn out or retiring
+    async fn is_page_retired(&self, page : usize) -> Result<bool,Error<S::Error>> {
+        Ok(self.read_field(self.page_field(page, PAGE_BAD)).await? == PAGE_STATE_SET)
+    }
+
+    async fn is_page_blank(&self, page : usize) -> Result<bool,Error<S::Error>> {
+        self.is_range_erased(self.page_start(page), self.page_end(page)).await
+    }
+
+    async fn page_seq(&self, page : usize) -> Result<u32,Error<S::Error>> {
+        self.read_field(self.page_field(page, PAGE_SEQ)).await
+    }
+
+    /// Compare page sequence numbers, taking overflow into account
+    async fn is_page_newer(&self, page : usize, than : usize) -> Result<bool,Error<S::Error>> {
+        Ok((self.page_seq(page).await?.wrapping_sub(self.page_seq(than).await?) as i32) > 0)
+    }
+
+    /// Active page with the lowest sequence number. Sequence field of other
+    /// pages, e.g. retired ones, means nothing.
+    async fn oldest_page(&self) -> Result<usize,Error<S::Error>> {
+        let mut oldest = None;
+        for page in 0 .. self.pages_num() {
+            if self.page_state(page).await? != PageState::Active {
+                continue;
+            }
+            match oldest {
+                Some(oldest) if !self.is_page_newer(oldest, page).await? => {}
+                _ => oldest = Some(page),
+            }
+        }
+        Ok(oldest.unwrap_or(self.page))
+    }
+
+    /// Activate erased page and move write position to it
+    async fn open_page(&mut self, page : usize, seq : u32) -> Result<(),Error<S::Error>> {
+        self.program_field(self.page_field(page, PAGE_SEQ), seq).await?;
+        self.set_page_state(page, PAGE_RECEIVING).await?;
+        self.set_page_state(page, PAGE_ACTIVE).await?;
+        self.page = page;
+        self.current = self.data_start(page);
+        Ok(())
+    }
+
+    /// Open page next to the head, bad pages are skipped. Erased pages enough
+    /// to take content of the largest page are reserved for `compact`.
+    async fn open_next_page(&mut self, use_reserve : bool) -> Result<(),Error<S::Error>> {
+        let pages_num = self.pages_num();
+        let mut next = None;
+        for page in (1 .. pages_num).map(|n| (self.page + n) % pages_num) {
+            if self.page_state(page).await? != PageState::Bad {
+                next = Some(page);
+                break;
+            }
+        }
+        let next = next.ok_or(Error::OutOfFreeSpace)?;
+        if self.page_state(next).await? != PageState::Erased {
+            return Err(Error::OutOfFreeSpace);
+        }
+
+        if !use_reserve {
+            let mut reserve_len = 0;
+            let mut erased_len = 0;
+            for page in 0 .. pages_num {
+                if !self.is_page_retired(page).await? {
+                    reserve_len = reserve_len.max(self.page_data_len(page));
+                }
+                if page != next && self.page_state(page).await? == PageState::Erased {
+                    erased_len += self.page_data_len(page);
+                }
+            }
+            if erased_len < reserve_len {
+                return Err(Error::OutOfFreeSpace);
+            }
+        }
+
+        let seq = self.page_seq(self.page).await?.wrapping_add(1);
+        self.open_page(next, seq).await
+    }
+
+    async fn set_page_state(&mut self, page : usize, state : usize) -> Result<(),Error<S::Error>> {
+        self.program_field(self.page_field(page, state), PAGE_STATE_SET).await
+    }
+
+    /// Erase page, page which is not blank after erase is retired regardless
+    /// of verify setting. Failed erase may be transient, so it is only reported.
+    async fn erase_page(&mut self, page : usize) -> Result<(),Error<S::Error>> {
+        self.storage.mem_erase(page).await.map_err(Error::Mem)?;
+        if !self.is_page_blank(page).await? {
+            return self.set_page_state(page, PAGE_BAD).await;
+        }
+        Ok(())
+    }
+
+    /// Page the record header is placed in
+    fn header_page(&self, header_idx : usize) -> usize {
+        self.storage.mem_geometry().sector_at(header_idx)
+    }
+
+    async fn header_fields(&self, header_idx : usize) -> Result<HeaderFields,Error<S::Error>> {
+        Ok(F::decode(&self.read_header(header_idx).await?))
+    }
+
+    /// Position and size in bytes of record payload
+    async fn payload_at(&self, record : &RecordDesc) -> Result<Option<(usize, usize)>,Error<S::Error>> {
+        match record.offset {
+            Some(header_idx) => {
+                let fields = self.header_fields(header_idx).await?;
+                // Basic sanity check
+                if fields.tag == record.tag {
+                    Ok(Some((header_idx + self.header_len(), fields.sz as usize)))
+                } else {
+                    Err(Error::CorruptedRecordOnGet)
+                }
+            },
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) async fn get_into_async(&self, record : &RecordDesc, buf : &mut [u8]) -> Result<Option<usize>,Error<S::Error>> {
+        match self.payload_at(record).await? {
+            Some((_, len)) if len > buf.len() => Err(Error::BufferTooSmall),
+            Some((payload_idx, len)) => {
+                self.read_bytes(payload_idx, &mut buf[.. len]).await?;
+                Ok(Some(len))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) async fn len_async(&self) -> Result<usize,Error<S::Error>> {
+        let mut closed_len = 0;
+        for page in 0 .. self.pages_num() {
+            if page != self.page && self.page_state(page).await? == PageState::Active {
+                closed_len += self.page_data_len(page);
+            }
+        }
+        Ok((closed_len + self.current.saturating_sub(self.data_start(self.page))) * size_of::<S::Word>())
+    }
+
+    pub(crate) async fn capacity_async(&self) -> Result<usize,Error<S::Error>> {
+        let mut retired_len = 0;
+        for page in 0 .. self.pages_num() {
+            if self.is_page_retired(page).await? {
+                retired_len += self.page_end(page) - self.page_start(page);
+            }
+        }
+        Ok((self.storage.mem_len() - retired_len) * size_of::<S::Word>())
+    }
+
+    async fn free_space_in_words(&self) -> Result<usize,Error<S::Error>> {
+        // Retiring head takes no new records
+        if self.is_page_retired(self.page).await? {
+            return Ok(0);
+        }
+        Ok(self.page_end(self.page).saturating_sub(self.current))
+    }
+
+    fn pages_num(&self) -> usize {
+        self.storage.mem_geometry().sectors_num()
+    }
+
+    fn page_start(&self, page : usize) -> usize {
+        self.storage.mem_geometry().sector_start(page)
+    }
+
+    fn page_end(&self, page : usize) -> usize {
+        let geometry = self.storage.mem_geometry();
+        geometry.sector_start(page) + geometry.sector_len(page)
+    }
+
+    /// Space for records in page
+    fn page_data_len(&self, page : usize) -> usize {
+        self.page_end(page) - self.data_start(page)
+    }
+
+    /// First record position in page
+    fn data_start(&self, page : usize) -> usize {
+        self.page_field(page, PAGE_HEADER_LEN)
+    }
+
+    /// Position of page header field, every field takes own program unit
+    fn page_field(&self, page : usize, field : usize) -> usize {
+        self.page_start(page) + field * self.align(Self::words_len(FIELD_SIZE))
+    }
+
+    fn is_erased(&self, word : S::Word) -> bool {
+        word == self.storage.mem_geometry().erased
+    }
+
+    /// Value of field read from erased memory
+    fn erased_field(&self) -> u32 {
+        let mut bytes = [0; FIELD_SIZE];
+        for chunk in bytes.chunks_mut(size_of::<S::Word>()) {
+            self.storage.mem_geometry().erased.to_bytes(chunk);
+        }
+        u32::from_ne_bytes(bytes)
+    }
+}
+
+impl<S : MappedMem, F : HeaderFormat> Storage<S, F> {
+
+    /// Get record payload, it borrows storage so it can't outlive any later
+    /// update or compaction
+    pub fn get(&self, record : &RecordDesc) -> Result<Option<&[u8]>,Error<S::Error>> {
+        match ready(self.payload_at(record))? {
+            Some((payload_idx, len)) => Ok(Some(self.payload_slice(payload_idx, len))),
+            None => Ok(None),
+        }
+    }
+
+    /// Payload of `len` bytes starting at word `payload_idx`
+    fn payload_slice(&self, payload_idx : usize, len : usize) -> &[u8] {
+        let payload = self.storage.read_slice(payload_idx, payload_idx + Self::words_len(len));
+        // Words are plain integers, so their bytes are always initialized
+        unsafe {
+            from_raw_parts(payload.as_ptr() as *const u8, len)
+        }
+    }
+}
+
+#[cfg(any(test, feature="test-def"))]
+pub use test_def::{TestMem, YieldMem, block_on};
+
+#[cfg(any(test, feature="test-def"))]
+mod test_def {
+    use super::*;
+
+    pub use crc::crc32::{Digest, Hasher32};
+
+    impl<W : Word> StorageHasher32<W> for Digest {
+        fn reset(&mut self) {
+            <Digest as Hasher32>::reset(self);
+        }
+
+        fn write(&mut self, words: &[W]) {
+            let bytes = unsafe { 
+                from_raw_parts(words.as_ptr() as *const u8, core::mem::size_of_val(words)) 
+            };
+            <Digest as Hasher32>::write(self, bytes);
+        }
+
+        fn sum(&self) -> u32 {
+            <Digest as Hasher32>::sum32(self)
+        }
+    }
+
+    // Four equal sectors
+    pub(crate) const TEST_SECTORS : &[SectorRegion] = &[
+        SectorRegion { count : 4, sector_len : 0x40 },
+    ];
+
+    // STM32F4 flash bank layout scaled down from bytes to words:
+    // four 16K sectors, one 64K sector, then 128K sectors
+    pub(crate) const TEST_MIXED_SECTORS : &[SectorRegion] = &[
+        SectorRegion { count : 4, sector_len : 0x40 },
+        SectorRegion { count : 1, sector_len : 0x100 },
+        SectorRegion { count : 3, sector_len : 0x200 },
+    ];
+
+    fn erase_sector<W : Word>(mem : &mut [W], geometry : Geometry<W>, sector : usize) {
+        let start = geometry.sector_start(sector);
+        for word in &mut mem[start .. start + geometry.sector_len(sector)] {
+            *word = geometry.erased;
+        }
+    }
+
+    // Like flash, every unit may be programmed only once after erase
+    fn program_unit<W : Word>(mem : &mut [W], geometry : Geometry<W>, offset : usize, words : &[W]) {
+        assert!(offset.is_multiple_of(geometry.program_unit) && words.len() == geometry.program_unit, "Unaligned write at {}", offset);
+        let unit = &mut mem[offset .. offset + words.len()];
+        assert!(unit.iter().all(|&word| word == geometry.erased), "Unit at {} is programmed twice", offset);
+        unit.copy_from_slice(words);
+    }
+
+    /// Flash in RAM of given sector layout and erased value, program unit is
+    /// one word
+    #[derive(Clone)]
+    pub struct TestMem<const N : usize = 0x100> ( pub [u32;N], pub Geometry<u32> );
+
+    impl TestMem {
+        /// Four equal erased sectors
+        pub fn new() -> Self {
+            Self::with_layout(TEST_SECTORS, !0)
+        }
+    }
+
+    impl Default for TestMem {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl<const N : usize> TestMem<N> {
+        /// Erased memory, `sectors` cover exactly `N` words
+        pub fn with_layout(sectors : &'static [SectorRegion], erased : u32) -> Self {
+            let geometry = Geometry { sectors, program_unit : 1, erased };
+            assert_eq!(sectors.iter().map(|region| region.count * region.sector_len).sum::<usize>(), N, "Sectors don't cover memory");
+            Self([erased;N], geometry)
+        }
+    }
+
+    impl<const N : usize> StorageMem for TestMem<N> {
+        type Word = u32;
+        type Error = ();
+
+        fn write(&mut self, offset_words : usize, words : &[u32]) -> Result<(), Self::Error> {
+            program_unit(&mut self.0, self.1, offset_words, words);
+            Ok(())
+        }
+
+        fn read(&self, offset_words : usize) -> Result<u32, Self::Error> {
+            Ok(self.0[offset_words])
+        }
+
+        fn erase(&mut self, sector : usize) -> Result<(), Self::Error> {
+            erase_sector(&mut self.0, self.1, sector);
+            Ok(())
+        }
+
+        fn geometry(&self) -> Geometry<u32> {
+            self.1
+        }
+
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+    }
+
+    impl<const N : usize> MappedMem for TestMem<N> {
+        fn read_slice(&self, offset_start : usize, offset_end : usize) -> &[u32] {
+            &self.0[offset_start .. offset_end]
+        }
+    }
+
+    /// Future which is pending at the first poll
+    struct YieldNow(bool);
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self : core::pin::Pin<&mut Self>, cx : &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    /// Async memory on top of sync one, every operation yields to executor
+    /// before it's done
+    pub struct YieldMem<M> {
+        pub mem    : M,
+        /// Number of times operations have yielded
+        pub yields : core::cell::Cell<usize>,
+    }
+
+    impl<M> YieldMem<M> {
+        pub fn new(mem : M) -> Self {
+            Self { mem, yields : core::cell::Cell::new(0) }
+        }
+
+        async fn yield_now(&self) {
+            self.yields.set(self.yields.get() + 1);
+            yield_now().await
+        }
+    }
+
+    /// Let executor poll other futures once
+    pub(crate) async fn yield_now() {
+        YieldNow(false).await
+    }
+
+    impl<M : StorageMem> AsyncStorageMem for YieldMem<M> {
+        type Word = M::Word;
+        type Error = M::Error;
+
+        async fn write(&mut self, offset_words : usize, words : &[M::Word]) -> Result<(), Self::Error> {
+            self.yield_now().await;
+            self.mem.write(offset_words, words)
+        }
+
+        async fn read_into(&self, offset_words : usize, words : &mut [M::Word]) -> Result<(), Self::Error> {
+            self.yield_now().await;
+            self.mem.read_into(offset_words, words)
+        }
+
+        async fn erase(&mut self, sector : usize) -> Result<(), Self::Error> {
+            self.yield_now().await;
+            self.mem.erase(sector)
+        }
+
+        fn geometry(&self) -> Geometry<M::Word> {
+            self.mem.geometry()
+        }
+
+        fn len(&self) -> usize {
+            self.mem.len()
+        }
+    }
+
+    /// Host executor: poll future until it completes
+    pub fn block_on<T>(future : impl Future<Output = T>) -> T {
+        let mut future = pin!(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(value) = future.as_mut().poll(&mut cx) {
+                return value;
+            }
+        }
+    }
+}
+
+
+#[allow(dead_code, unused_imports)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_def::{TEST_SECTORS, TEST_MIXED_SECTORS};
+    use crc::crc32::{Digest, IEEE_TABLE, IEEE, Hasher32};
+    use crc::CalcType;
+    
+    fn crc32_ethernet() -> impl StorageHasher32 {
+        Digest::new_custom(IEEE, !0u32, 0u32, CalcType::Normal)
+    }
+
+    const HEADER_LEN : usize = FullHeader::LEN;
+    const WORD_SIZE : usize = size_of::<u32>();
+
+    fn as_bytes(words : &[u32]) -> &[u8] {
+        unsafe { from_raw_parts(words.as_ptr() as *const u8, words.len() * WORD_SIZE) }
+    }
+
+    fn new_storage() -> Storage<TestMem> {
+        let mut storage = Storage::new(TestMem::new());
+        storage.init(&mut [], &mut crc32_ethernet()).unwrap();
+        storage
+    }
+
+    /// Compact header of records with payload up to 8 bytes
+    struct ShortHeader;
+
+    impl HeaderFormat for ShortHeader {
+        const LEN : usize = CompactHeader::LEN;
+        const SYNC : u32 = CompactHeader::SYNC;
+        const SYNC_MASK : u32 = CompactHeader::SYNC_MASK;
+        const MAX_TAG : u32 = CompactHeader::MAX_TAG;
+        const MAX_SZ : u32 = 8;
+        const CRC_MASK : u32 = CompactHeader::CRC_MASK;
+
+        fn encode(fields : &HeaderFields, check : u32) -> [u32; MAX_HEADER_LEN] {
+            CompactHeader::encode(fields, check)
+        }
+
+        fn decode(words : &[u32]) -> HeaderFields {
+            CompactHeader::decode(words)
+        }
+    }
+
+    /// Memory that loses power after given number of write/erase operations,
+    /// all following operations are silently dropped
+    pub(crate) struct PowerLossMem {
+        pub(crate) mem        : TestMem,
+        pub(crate) ops_before_loss : usize,
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct WriteFault(usize);
+
+    /// Memory that fails every write and erase when `faulty` is set, the last
+    /// word of `worn_sector` is stuck at zero after erase
+    struct FaultyMem {
+        mem    : TestMem,
+        faulty : bool,
+        worn_sector : Option<usize>,
+    }
+
+    impl StorageMem for FaultyMem {
+        type Word = u32;
+        type Error = WriteFault;
+
+        fn write(&mut self, offset_words : usize, words : &[u32]) -> Result<(), Self::Error> {
+            if self.faulty {
+                return Err(WriteFault(offset_words));
+            }
+            self.mem.write(offset_words, words).map_err(|_| WriteFault(offset_words))
+        }
+
+        fn read(&self, offset_words : usize) -> Result<u32, Self::Error> {
+            self.mem.read(offset_words).map_err(|_| WriteFault(offset_words))
+        }
+
+        fn erase(&mut self, sector : usize) -> Result<(), Self::Error> {
+            let geometry = self.geometry();
+            if self.faulty {
+                return Err(WriteFault(geometry.sector_start(sector)));
+            }
+            self.mem.erase(sector).map_err(|_| WriteFault(0))?;
+            if self.worn_sector == Some(sector) {
+                self.mem.0[geometry.sector_start(sector) + geometry.sector_len(sector) - 1] = 0;
+            }
+            Ok(())
+        }
+
+        fn geometry(&self) -> Geometry<u32> {
+            self.mem.geometry()
+        }
+
+        fn len(&self) -> usize {
+            self.mem.len()
+        }
+    }
+
+    impl MappedMem for FaultyMem {
+        fn read_slice(&self, offset_start : usize, offset_end : usize) -> &[u32] {
+            self.mem.read_slice(offset_start, offset_end)
+        }
+    }
+
+    /// Memory which is not memory mapped, like SPI flash
+    struct ExternalMem {
+        mem : TestMem,
+    }
+
+    impl StorageMem for ExternalMem {
+        type Word = u32;
+        type Error = ();
+
+        fn write(&mut self, offset_words : usize, words : &[u32]) -> Result<(), Self::Error> {
+            self.mem.write(offset_words, words)
+        }
+
+        fn read(&self, offset_words : usize) -> Result<u32, Self::Error> {
+            self.mem.read(offset_words)
+        }
+
+        fn read_into(&self, offset_words : usize, words : &mut [u32]) -> Result<(), Self::Error> {
+            words.copy_from_slice(&self.mem.0[offset_words .. offset_words + words.len()]);
+            Ok(())
+        }
+
+        fn erase(&mut self, sector : usize) -> Result<(), Self::Error> {
+            self.mem.erase(sector)
+        }
+
+        fn geometry(&self) -> Geometry<u32> {
+            self.mem.geometry()
+        }
+
+        fn len(&self) -> usize {
+            self.mem.len()
+        }
+    }
+
+    /// Memory with worn cells which lose lower bits of written words
+    struct WornMem {
+        mem  : TestMem,
+        worn : core::ops::Range<usize>,
+    }
+
+    impl StorageMem for WornMem {
+        type Word = u32;
+        type Error = ();
+
+        fn write(&mut self, offset_words : usize, words : &[u32]) -> Result<(), Self::Error> {
+            if self.worn.contains(&offset_words) {
+                return self.mem.write(offset_words, &[words[0] & 0xFFFF_FF00]);
+            }
+            self.mem.write(offset_words, words)
+        }
+
+        fn read(&self, offset_words : usize) -> Result<u32, Self::Error> {
+            self.mem.read(offset_words)
+        }
+
+        fn erase(&mut self, sector : usize) -> Result<(), Self::Error> {
+            self.mem.erase(sector)
+        }
+
+        fn geometry(&self) -> Geometry<u32> {
+            self.mem.geometry()
+        }
+
+        fn len(&self) -> usize {
+            self.mem.len()
+        }
+    }
+
+    impl MappedMem for WornMem {
+        fn read_slice(&self, offset_start : usize, offset_end : usize) -> &[u32] {
+            self.mem.read_slice(offset_start, offset_end)
+        }
+    }
+
+    /// Memory programmed by units of given number of words
+    struct WideMem {
+        mem  : TestMem<0x800>,
+        unit : usize,
+    }
+
+    impl StorageMem for WideMem {
+        type Word = u32;
+        type Error = ();
+
+        fn write(&mut self, offset_words : usize, words : &[u32]) -> Result<(), Self::Error> {
+            assert!(offset_words.is_multiple_of(self.unit) && words.len() == self.unit);
+            for (idx, word) in words.iter().enumerate() {
+                self.mem.write(offset_words + idx, &[*word])?;
+            }
+            Ok(())
+        }
+
+        fn read(&self, offset_words : usize) -> Result<u32, Self::Error> {
+            self.mem.read(offset_words)
+        }
+
+        fn erase(&mut self, sector : usize) -> Result<(), Self::Error> {
+            self.mem.erase(sector)
+        }
+
+        fn geometry(&self) -> Geometry<u32> {
+            Geometry { program_unit : self.unit, ..self.mem.geometry() }
+        }
+
+        fn len(&self) -> usize {
+            self.mem.len()
+        }
+    }
+
+    impl MappedMem for WideMem {
+        fn read_slice(&self, offset_start : usize, offset_end : usize) -> &[u32] {
+            self.mem.read_slice(offset_start, offset_end)
+        }
+    }
+
+    // Four equal sectors of `ArrayMem`
+    const ARRAY_SECTORS : &[SectorRegion] = &[
+        SectorRegion { count : 4, sector_len : 0x100 },
+    ];
+
+    /// Memory of words of any type
+    struct ArrayMem<W> {
+        mem          : [W; 0x400],
+        erased       : W,
+        program_unit : usize,
+    }
+
+    impl<W : Word> StorageMem for ArrayMem<W> {
+        type Word = W;
+        type Error = ();
+
+        fn write(&mut self, offset_words : usize, words : &[W]) -> Result<(), Self::Error> {
+            assert!(offset_words.is_multiple_of(self.program_unit) && words.len() == self.program_unit);
+            let erased = self.erased;
+            let unit = &mut self.mem[offset_words .. offset_words + words.len()];
+            assert!(unit.iter().all(|&word| word == erased), "Unit at {} is programmed twice", offset_words);
+            unit.copy_from_slice(words);
+            Ok(())
+        }
+
+        fn read(&self, offset_words : usize) -> Result<W, Self::Error> {
+            Ok(self.mem[offset_words])
+        }
+
+        fn erase(&mut self, sector : usize) -> Result<(), Self::Error> {
+            let start = sector * 0x100;
+            for word in &mut self.mem[start .. start + 0x100] {
+                *word = self.erased;
+            }
+            Ok(())
+        }
+
+        fn geometry(&self) -> Geometry<W> {
+            Geometry {
+                sectors      : ARRAY_SECTORS,
+                program_unit : self.program_unit,
+                erased       : self.erased,
+            }
+        }
+
+        fn len(&self) -> usize {
+            self.mem.len()
+        }
+    }
+
+    impl<W : Word> MappedMem for ArrayMem<W> {
+        fn read_slice(&self, offset_start : usize, offset_end : usize) -> &[W] {
+            &self.mem[offset_start .. offset_end]
+        }
+    }
+
+    impl StorageMem for PowerLossMem {
+        type Word = u32;
+        type Error = ();
+
+        fn write(&mut self, offset_words : usize, words : &[u32]) -> Result<(), Self::Error> {
+            if self.ops_before_loss == 0 {
+                return Ok(());
+            }
+            self.ops_before_loss -= 1;
+            self.mem.write(offset_words, words)
+        }
+
+        fn read(&self, offset_words : usize) -> Result<u32, Self::Error> {
+            self.mem.read(offset_words)
+        }
+
+        fn erase(&mut self, sector : usize) -> Result<(), Self::Error> {
+            if self.ops_before_loss == 0 {
+                return Ok(());
+            }
+            self.ops_before_loss -= 1;
+            self.mem.erase(sector)
+        }
+
+        fn geometry(&self) -> Geometry<u32> {
+            self.mem.geometry()
+        }
+
+        fn len(&self) -> usize {
+            self.mem.len()
+        }
+    }
+
+    impl MappedMem for PowerLossMem {
+        fn read_slice(&self, offset_start : usize, offset_end : usize) -> &[u32] {
+            self.mem.read_slice(offset_start, offset_end)
+        }
+    }
+
+    #[test]
+    fn empty_test() {
+        let mem = TestMem::new();
+        let capacity = mem.0.len() * WORD_SIZE;
+        let storage = Storage::new(mem);
+
+        assert_eq!(storage.len().unwrap(), 0);
+        assert_eq!(storage.capacity().unwrap(), capacity);
+    }
+
+    #[test]
+    fn not_initialized_test() {
+        let mut storage = Storage::new(TestMem::new());
+        let mut crc32 = crc32_ethernet();
+        let mut desc_list = [
+            RecordDesc {
+                tag : 0,
+                offset : None,
+            },
+        ];
+
+        // Nothing is written before `init`
+        match storage.update(&mut desc_list[0], &[1, 2, 3, 4], &mut crc32) {
+            Err(Error::NotInitialized) => {}
+            res => panic!("{:?}", res),
+        }
+        match storage.update_or_compact(&mut desc_list, 0, &[1, 2, 3, 4], &mut crc32) {
+            Err(Error::NotInitialized) => {}
+            res => panic!("{:?}", res),
+        }
+        match storage.compact(&mut desc_list, &mut crc32) {
+            Err(Error::NotInitialized) => {}
+            res => panic!("{:?}", res),
+        }
+        assert!(storage.storage.0.iter().all(|&word| word == !0));
+
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        storage.update(&mut desc_list[0], &[1, 2, 3, 4], &mut crc32).unwrap();
+        let stats = storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(stats.unique_tags, 1);
+        assert_eq!(storage.get(&desc_list[0]).unwrap().unwrap(), &[1, 2, 3, 4]);
+    }
+    
+    #[test]
     fn new_record_test() {
         let mut storage = new_storage();
-        let mut rec_desc = RecordDesc {
-            tag : 1,
-            ptr : None,
-        };
+        let mut rec_desc = RecordDesc {
+            tag : 1,
+            offset : None,
+        };
+
+        let rec_payload = [42u32;1];
+        let mut crc32 = crc32_ethernet();
+        
+        storage.update(&mut rec_desc, as_bytes(&rec_payload), &mut crc32).unwrap();
+        assert_eq!(storage.len().unwrap(), (HEADER_LEN + rec_payload.len() + COMMIT_LEN) * WORD_SIZE );
+        assert!(&rec_desc.offset.is_some());
+        
+        let out_rec_payload = storage.get(&rec_desc).unwrap().unwrap();
+        //println!("Desc list : {:#?}", &rec_desc);
+        assert_eq!(as_bytes(&rec_payload), out_rec_payload);
+
+
+        let mut desc_list = [
+            RecordDesc {
+                tag : 0,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 1,
+                offset : None,
+            },
+        ];
+        let _stats = storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(&desc_list[1], &rec_desc);
+        //println!("Desc list : {:#?}", &desc_list);
+    }
+
+    #[test]
+    fn series_of_records_test() {
+        let mut storage = new_storage();
+        let mut crc32 = crc32_ethernet();
+
+        let mut desc_list = [
+            RecordDesc {
+                tag : 0,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 1,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 2,
+                offset : None,
+            },
+        ];
+
+        let e0 = [!42u32; 10];
+        storage.update(&mut desc_list[0], as_bytes(&e0), &mut crc32).unwrap();
+
+        let e1 = [0x7777_7777; 3];
+        storage.update(&mut desc_list[1], as_bytes(&e1), &mut crc32).unwrap();
+
+        let e0 = [0x6666_6666; 3];
+        storage.update(&mut desc_list[0], as_bytes(&e0), &mut crc32).unwrap();
+
+        let e2 = [0x5555_5555; 3];
+        storage.update(&mut desc_list[2], as_bytes(&e2), &mut crc32).unwrap();
+
+        let e0 = [0xA5B5A5A5u32; 2];
+        storage.update(&mut desc_list[0], as_bytes(&e0), &mut crc32).unwrap();
+
+        let e1 = [66u32; 5];
+        storage.update(&mut desc_list[1], as_bytes(&e1), &mut crc32).unwrap();
+        
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(storage.get(&desc_list[0]).unwrap().unwrap(), as_bytes(&e0));
+        assert_eq!(storage.get(&desc_list[1]).unwrap().unwrap(), as_bytes(&e1));
+        assert_eq!(storage.get(&desc_list[2]).unwrap().unwrap(), as_bytes(&e2));
+        
+        //println!("Desc list : {:#?}", &desc_list);
+    }
+
+    #[test]
+    fn compaction_test() {
+        let mut storage = new_storage();
+        let mut crc32 = crc32_ethernet();
+
+        let mut desc_list = [
+            RecordDesc {
+                tag : 0,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 1,
+                offset : None,
+            },
+        ];
+
+        let e1 = [0x7777_7777; 3];
+        storage.update(&mut desc_list[1], as_bytes(&e1), &mut crc32).unwrap();
+
+        // Fill storage with outdated versions of the same record
+        let mut counter = 0u32;
+        loop {
+            match storage.update(&mut desc_list[0], as_bytes(&[counter; 4]), &mut crc32) {
+                Ok(()) => counter += 1,
+                Err(Error::OutOfFreeSpace) => break,
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+        let e0 = [counter - 1; 4];
+
+        // One page is kept in reserve
+        let erased = (0 .. storage.pages_num()).filter(|&page| ready(storage.page_state(page)).unwrap() == PageState::Erased);
+        assert_eq!(erased.count(), 1);
+
+        let len = storage.len().unwrap();
+        storage.compact(&mut desc_list, &mut crc32).unwrap();
+        assert!(storage.len().unwrap() < len);
+        assert_eq!(ready(storage.page_state(0)).unwrap(), PageState::Erased);
+        assert_eq!(storage.get(&desc_list[0]).unwrap().unwrap(), as_bytes(&e0));
+        assert_eq!(storage.get(&desc_list[1]).unwrap().unwrap(), as_bytes(&e1));
+
+        // Appending resumes after compaction
+        let e0 = [counter; 4];
+        storage.update(&mut desc_list[0], as_bytes(&e0), &mut crc32).unwrap();
+
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(storage.get(&desc_list[0]).unwrap().unwrap(), as_bytes(&e0));
+        assert_eq!(storage.get(&desc_list[1]).unwrap().unwrap(), as_bytes(&e1));
+    }
+
+    #[test]
+    fn update_or_compact_test() {
+        let mut storage = new_storage();
+        let mut crc32 = crc32_ethernet();
+
+        let mut desc_list = [
+            RecordDesc {
+                tag : 0,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 1,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 2,
+                offset : None,
+            },
+        ];
+
+        let e2 = [0x5555_5555; 7];
+        storage.update(&mut desc_list[2], as_bytes(&e2), &mut crc32).unwrap();
+
+        // Run around the page ring several times
+        for counter in 0 .. 500u32 {
+            let tag = counter % 2;
+            storage.update_or_compact(&mut desc_list, tag, as_bytes(&[counter; 5]), &mut crc32).unwrap();
+            if counter % 77 == 0 {
+                storage.init(&mut desc_list, &mut crc32).unwrap();
+            }
+        }
+
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(storage.get(&desc_list[0]).unwrap().unwrap(), as_bytes(&[498; 5]));
+        assert_eq!(storage.get(&desc_list[1]).unwrap().unwrap(), as_bytes(&[499; 5]));
+        assert_eq!(storage.get(&desc_list[2]).unwrap().unwrap(), as_bytes(&e2));
+
+        // Record larger than a page is refused before anything is reclaimed
+        let snapshot = storage.storage.clone();
+        match storage.update_or_compact(&mut desc_list, 0, &[0; 0x40 * WORD_SIZE], &mut crc32) {
+            Err(Error::RecordTooLarge) => {}
+            res => panic!("{:?}", res),
+        }
+        assert_eq!(storage.storage.0[..], snapshot.0[..]);
+    }
+
+    #[test]
+    fn test_mem_erase_test() {
+        let mut mem = TestMem::new();
+        mem.0.fill(0);
+        let geometry = mem.geometry();
+        let sector_len = geometry.sector_len(1);
+        assert_eq!(mem.sectors_num(), 0x100 / sector_len);
+
+        mem.erase(1).unwrap();
+        assert!(mem.0[.. sector_len].iter().all(|&word| word == 0));
+        assert!(mem.0[sector_len .. 2 * sector_len].iter().all(|&word| word == geometry.erased));
+        assert!(mem.0[2 * sector_len ..].iter().all(|&word| word == 0));
+    }
+
+    #[test]
+    fn mixed_geometry_test() {
+        let geometry = TestMem::<0x800>::with_layout(TEST_MIXED_SECTORS, !0).geometry();
+        assert_eq!(geometry.sectors_num(), 8);
+        assert_eq!(geometry.sector_len(3), 0x40);
+        assert_eq!(geometry.sector_len(4), 0x100);
+        assert_eq!(geometry.sector_len(7), 0x200);
+        assert_eq!(geometry.sector_start(4), 0x100);
+        assert_eq!(geometry.sector_start(6), 0x400);
+        assert_eq!(geometry.sector_at(0x3f), 0);
+        assert_eq!(geometry.sector_at(0x100), 4);
+        assert_eq!(geometry.sector_at(0x1ff), 4);
+        assert_eq!(geometry.sector_at(0x7ff), 7);
+    }
+
+    #[test]
+    fn mixed_sectors_test() {
+        let mut storage = Storage::new(TestMem::<0x800>::with_layout(TEST_MIXED_SECTORS, !0));
+        let mut crc32 = crc32_ethernet();
+
+        let mut desc_list = [
+            RecordDesc {
+                tag : 0,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 1,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 2,
+                offset : None,
+            },
+        ];
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+
+        let e2 = [0x5555_5555; 0x30];
+        storage.update(&mut desc_list[2], as_bytes(&e2), &mut crc32).unwrap();
+
+        // Every page, small and large, is reclaimed several times
+        for counter in 0 .. 2000u32 {
+            let tag = counter % 2;
+            storage.update_or_compact(&mut desc_list, tag, as_bytes(&[counter; 9]), &mut crc32).unwrap();
+            if counter % 333 == 0 {
+                storage.init(&mut desc_list, &mut crc32).unwrap();
+            }
+        }
+
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(storage.get(&desc_list[0]).unwrap().unwrap(), as_bytes(&[1998; 9]));
+        assert_eq!(storage.get(&desc_list[1]).unwrap().unwrap(), as_bytes(&[1999; 9]));
+        assert_eq!(storage.get(&desc_list[2]).unwrap().unwrap(), as_bytes(&e2));
+    }
+
+    #[test]
+    fn page_states_test() {
+        let mut storage = new_storage();
+        assert_eq!(storage.page, 0);
+        assert_eq!(ready(storage.page_state(0)).unwrap(), PageState::Active);
+        assert_eq!(ready(storage.page_state(1)).unwrap(), PageState::Erased);
+
+        let mut crc32 = crc32_ethernet();
+        let mut desc_list = [
+            RecordDesc {
+                tag : 0,
+                offset : None,
+            },
+        ];
+        storage.update(&mut desc_list[0], as_bytes(&[1, 2]), &mut crc32).unwrap();
+        storage.compact(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(storage.page, 1);
+        assert_eq!(ready(storage.page_state(0)).unwrap(), PageState::Erased);
+        assert_eq!(ready(storage.page_state(1)).unwrap(), PageState::Active);
+        assert!(ready(storage.is_page_blank(0)).unwrap());
+    }
+
+    #[test]
+    fn interrupted_compaction_test() {
+        let mut crc32 = crc32_ethernet();
+        let mut desc_list = [
+            RecordDesc {
+                tag : 0,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 1,
+                offset : None,
+            },
+        ];
+
+        let mut storage = new_storage();
+        let e1 = [0x7777_7777; 3];
+        storage.update(&mut desc_list[1], as_bytes(&e1), &mut crc32).unwrap();
+        let mut counter = 0u32;
+        while storage.update(&mut desc_list[0], as_bytes(&[counter; 4]), &mut crc32).is_ok() {
+            counter += 1;
+        }
+        let e0 = [counter - 1; 4];
+        let snapshot = storage.storage.clone();
+
+        // Cut power at every possible step of compaction
+        let mut ops_before_loss = 0;
+        loop {
+            let mut storage = Storage::new(PowerLossMem { mem : snapshot.clone(), ops_before_loss });
+            let mut list = desc_list;
+            storage.init(&mut list, &mut crc32).unwrap();
+            storage.compact(&mut list, &mut crc32).unwrap();
+            let finished = storage.storage.ops_before_loss > 0;
+
+            let mut storage = Storage::new(storage.storage.mem);
+            let mut list = desc_list;
+            storage.init(&mut list, &mut crc32).unwrap();
+            assert_eq!(storage.get(&list[0]).unwrap().unwrap(), as_bytes(&e0));
+            assert_eq!(storage.get(&list[1]).unwrap().unwrap(), as_bytes(&e1));
+
+            // Storage is still usable
+            storage.update_or_compact(&mut list, 0, as_bytes(&[counter; 4]), &mut crc32).unwrap();
+            storage.init(&mut list, &mut crc32).unwrap();
+            assert_eq!(storage.get(&list[0]).unwrap().unwrap(), as_bytes(&[counter; 4]));
+
+            if finished {
+                break;
+            }
+            ops_before_loss += 1;
+        }
+    }
+
+    #[test]
+    fn torn_record_test() {
+        let mut crc32 = crc32_ethernet();
+        let mut desc_list = [
+            RecordDesc {
+                tag : 0,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 1,
+                offset : None,
+            },
+        ];
+
+        let mut storage = new_storage();
+        let e0 = [0x1234_5678; 3];
+        storage.update(&mut desc_list[0], as_bytes(&e0), &mut crc32).unwrap();
+        let snapshot = storage.storage.clone();
+
+        // Cut power at every possible step of record write
+        let e1 = [0x7777_7777; 4];
+        for ops_before_loss in 0 .. storage.record_len(e1.len() * WORD_SIZE) {
+            let mut storage = Storage::new(PowerLossMem { mem : snapshot.clone(), ops_before_loss });
+            let mut list = desc_list;
+            storage.init(&mut list, &mut crc32).unwrap();
+            storage.update(&mut list[1], as_bytes(&e1), &mut crc32).unwrap();
+
+            let mut storage = Storage::new(storage.storage.mem);
+            let mut list = desc_list;
+            let stats = storage.init(&mut list, &mut crc32).unwrap();
+            assert_eq!(storage.get(&list[0]).unwrap().unwrap(), as_bytes(&e0));
+            assert_eq!(storage.get(&list[1]).unwrap(), None);
+            assert_eq!(stats.torn_records, if ops_before_loss == 0 { 0 } else { 1 });
+            assert_eq!(stats.words_wasted, 0);
+
+            // Torn record is skipped and never overwritten
+            storage.update(&mut list[1], as_bytes(&e1), &mut crc32).unwrap();
+            let stats = storage.init(&mut list, &mut crc32).unwrap();
+            assert_eq!(storage.get(&list[0]).unwrap().unwrap(), as_bytes(&e0));
+            assert_eq!(storage.get(&list[1]).unwrap().unwrap(), as_bytes(&e1));
+            assert_eq!(stats.torn_records, if ops_before_loss == 0 { 0 } else { 1 });
+        }
+    }
+
+    #[test]
+    fn embedded_record_test() {
+        let mut crc32 = crc32_ethernet();
+        let mut desc_list = [
+            RecordDesc {
+                tag : 0,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 1,
+                offset : None,
+            },
+        ];
+
+        // Image of valid record placed into payload of another one
+        let mut scratch = new_storage();
+        scratch.update(&mut desc_list[1], as_bytes(&[0x7777_7777; 2]), &mut crc32).unwrap();
+        let start = scratch.data_start(0);
+        let mut payload = [0; 12];
+        payload[1 .. 9].copy_from_slice(&scratch.storage.0[start .. start + 8]);
+
+        let mut storage = new_storage();
+        storage.update(&mut desc_list[0], as_bytes(&payload), &mut crc32).unwrap();
+        let stats = storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(storage.get(&desc_list[0]).unwrap().unwrap(), as_bytes(&payload));
+        assert_eq!(storage.get(&desc_list[1]).unwrap(), None);
+        assert_eq!(stats.unique_tags, 1);
+
+        // Damaged record is skipped as a whole
+        let start = storage.data_start(0);
+        storage.storage.0[start + HEADER_LEN + 11] ^= 1;
+        let stats = storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(storage.get(&desc_list[0]).unwrap(), None);
+        assert_eq!(storage.get(&desc_list[1]).unwrap(), None);
+        assert_eq!(stats.corrupted_records, 1);
+        assert_eq!(stats.unique_tags, 0);
+    }
+
+    #[test]
+    fn resync_test() {
+        let mut crc32 = crc32_ethernet();
+        let mut desc_list = [
+            RecordDesc {
+                tag : 0,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 1,
+                offset : None,
+            },
+        ];
+
+        let mut storage = new_storage();
+        let e0 = [0x1234_5678; 3];
+        storage.update(&mut desc_list[0], as_bytes(&e0), &mut crc32).unwrap();
+        let e1 = [0x7777_7777; 4];
+        storage.update(&mut desc_list[1], as_bytes(&e1), &mut crc32).unwrap();
 
-        let rec_payload = [42u32;1];
+        // Damaged header of the first record
+        let start = storage.data_start(0);
+        storage.storage.0[start] = 0;
+        storage.storage.0[start + 2] = 0x1CED_1CED;
+        let stats = storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(storage.get(&desc_list[0]).unwrap(), None);
+        assert_eq!(storage.get(&desc_list[1]).unwrap().unwrap(), as_bytes(&e1));
+        assert_eq!(stats.unique_tags, 1);
+    }
+
+    #[test]
+    fn mem_error_test() {
         let mut crc32 = crc32_ethernet();
-        
-        storage.update(&mut rec_desc, &rec_payload, &mut crc32).unwrap();
-        assert_eq!(storage.len(), (HEADER_LEN + rec_payload.len()) * WORD_SIZE );
-        assert!(&rec_desc.ptr.is_some());
-        
-        let out_rec_payload = storage.get(&rec_desc).unwrap().unwrap();
-        //println!("Desc list : {:#?}", &rec_desc);
-        assert_eq!(&rec_payload, out_rec_payload);
+        let mut desc_list = [
+            RecordDesc {
+                tag : 0,
+                offset : None,
+            },
+        ];
+
+        // Formatting fresh memory fails
+        let mut storage = Storage::new(FaultyMem { mem : TestMem::new(), faulty : true, worn_sector : None });
+        match storage.init(&mut desc_list, &mut crc32) {
+            Err(Error::Mem(WriteFault(_))) => {}
+            res => panic!("{:?}", res),
+        }
 
+        storage.storage.faulty = false;
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        storage.update(&mut desc_list[0], as_bytes(&[1, 2, 3]), &mut crc32).unwrap();
+
+        storage.storage.faulty = true;
+        let current = storage.current;
+        match storage.update(&mut desc_list[0], as_bytes(&[4, 5, 6]), &mut crc32) {
+            Err(Error::Mem(WriteFault(offset))) => assert_eq!(offset, current + HEADER_LEN - 1),
+            res => panic!("{:?}", res),
+        }
+        assert_eq!(storage.get(&desc_list[0]).unwrap().unwrap(), as_bytes(&[1, 2, 3]));
+
+        // Failed record space is skipped
+        storage.storage.faulty = false;
+        storage.update(&mut desc_list[0], as_bytes(&[4, 5, 6]), &mut crc32).unwrap();
+        assert_eq!(storage.current, current + 2 * storage.record_len(3 * WORD_SIZE));
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(storage.get(&desc_list[0]).unwrap().unwrap(), as_bytes(&[4, 5, 6]));
+    }
 
+    #[test]
+    fn write_verify_test() {
+        let mut crc32 = crc32_ethernet();
         let mut desc_list = [
             RecordDesc {
                 tag : 0,
-                ptr : None,
+                offset : None,
             },
             RecordDesc {
                 tag : 1,
-                ptr : None,
+                offset : None,
             },
         ];
-        let _stats = storage.init(&mut desc_list, &mut crc32);
-        assert_eq!(&desc_list[1], &rec_desc);
-        //println!("Desc list : {:#?}", &desc_list);
+
+        let mut storage = Storage::new(WornMem { mem : TestMem::new(), worn : 0 .. 0 });
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        let e0 = [0x1234_5678; 3];
+        storage.update(&mut desc_list[0], as_bytes(&e0), &mut crc32).unwrap();
+
+        // Second payload word of the next record is worn
+        let current = storage.current;
+        let record_len = storage.record_len(4 * WORD_SIZE);
+        storage.storage.worn = current + HEADER_LEN + 1 .. current + HEADER_LEN + 2;
+        storage.set_verify(true);
+
+        let e1 = [0x7777_7777; 4];
+        storage.update(&mut desc_list[1], as_bytes(&e1), &mut crc32).unwrap();
+        assert_eq!(storage.current, current + 2 * record_len);
+        assert_eq!(storage.get(&desc_list[1]).unwrap().unwrap(), as_bytes(&e1));
+
+        let stats = storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(stats.discarded_records, 1);
+        assert_eq!(stats.torn_records, 0);
+        assert_eq!(storage.get(&desc_list[0]).unwrap().unwrap(), as_bytes(&e0));
+        assert_eq!(storage.get(&desc_list[1]).unwrap().unwrap(), as_bytes(&e1));
+
+        // Whole region is worn out, record is written again in the next page
+        let current = storage.current;
+        storage.storage.worn = current .. current + WRITE_ATTEMPTS * record_len;
+        let e2 = [0x6666_6666; 4];
+        storage.update(&mut desc_list[1], as_bytes(&e2), &mut crc32).unwrap();
+        assert_eq!(storage.page, 1);
+        assert_eq!(storage.get(&desc_list[1]).unwrap().unwrap(), as_bytes(&e2));
+
+        // Worn page is retired
+        assert_eq!(ready(storage.page_state(0)).unwrap(), PageState::Active);
+        assert!(ready(storage.is_page_retired(0)).unwrap());
+        storage.storage.worn = 0 .. 0;
+        let stats = storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(stats.retired_sectors, 1);
+        assert_eq!(storage.get(&desc_list[0]).unwrap().unwrap(), as_bytes(&e0));
+        assert_eq!(storage.get(&desc_list[1]).unwrap().unwrap(), as_bytes(&e2));
+
+        // Retiring page is reclaimed, but never erased
+        storage.compact(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(ready(storage.page_state(0)).unwrap(), PageState::Bad);
+        assert_eq!(storage.get(&desc_list[0]).unwrap().unwrap(), as_bytes(&e0));
+
+        // Without verification worn record is committed, but rejected by `init`
+        let mut storage = Storage::new(WornMem { mem : TestMem::new(), worn : 0 .. 0 });
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        let current = storage.current;
+        storage.storage.worn = current + HEADER_LEN .. current + HEADER_LEN + 1;
+        storage.update(&mut desc_list[1], as_bytes(&e1), &mut crc32).unwrap();
+        let stats = storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(stats.corrupted_records, 1);
+        assert_eq!(storage.get(&desc_list[1]).unwrap(), None);
     }
 
     #[test]
-    fn series_of_records_test() {
-        let mut storage = new_storage();
+    fn bad_sector_test() {
+        let mut crc32 = crc32_ethernet();
+        let mut desc_list = [
+            RecordDesc {
+                tag : 0,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 1,
+                offset : None,
+            },
+        ];
+
+        // Blank check after erase doesn't depend on verify
+        let mut storage = Storage::new(FaultyMem { mem : TestMem::new(), faulty : false, worn_sector : Some(0) });
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        let capacity = storage.capacity().unwrap();
+        let e1 = [0x7777_7777; 3];
+        storage.update(&mut desc_list[1], as_bytes(&e1), &mut crc32).unwrap();
+
+        // Ring keeps going around the sector which isn't blank after erase
+        for counter in 0 .. 100u32 {
+            storage.update_or_compact(&mut desc_list, 0, as_bytes(&[counter; 4]), &mut crc32).unwrap();
+        }
+        assert_eq!(ready(storage.page_state(0)).unwrap(), PageState::Bad);
+        assert_eq!(storage.capacity().unwrap(), capacity - 0x40 * WORD_SIZE);
+
+        let stats = storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(stats.retired_sectors, 1);
+        assert_eq!(stats.retired_words, 0x40);
+        assert_eq!(storage.get(&desc_list[0]).unwrap().unwrap(), as_bytes(&[99; 4]));
+        assert_eq!(storage.get(&desc_list[1]).unwrap().unwrap(), as_bytes(&e1));
+
+        // Retired sector is skipped when formatting
+        let mut mem = TestMem::new();
+        mem.0[PAGE_BAD] = PAGE_STATE_SET;
+        let mut storage = Storage::new(mem);
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(storage.page, 1);
+        assert_eq!(ready(storage.page_state(0)).unwrap(), PageState::Bad);
+
+        // Pages are replayed in order after reboot once log wraps around
+        // retired sector
+        for counter in 0 .. 20u32 {
+            storage.update_or_compact(&mut desc_list, 0, as_bytes(&[counter; 4]), &mut crc32).unwrap();
+        }
+        let head = storage.page;
+        assert!(ready(storage.oldest_page()).unwrap() > head);
+        let mut storage = Storage::new(storage.storage);
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(storage.page, head);
+        assert_eq!(storage.get(&desc_list[0]).unwrap().unwrap(), as_bytes(&[19; 4]));
+
+        // Failed erase is reported and sector is kept in use
+        let mut mem = TestMem::new();
+        mem.0[0x40] = 0x1234_5678;
+        let mut storage = Storage::new(FaultyMem { mem, faulty : true, worn_sector : None });
+        storage.set_verify(true);
+        match storage.init(&mut desc_list, &mut crc32) {
+            Err(Error::Mem(WriteFault(0x40))) => {}
+            res => panic!("{:?}", res),
+        }
+        storage.storage.faulty = false;
+        let stats = storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(stats.retired_sectors, 0);
+        assert_eq!(ready(storage.page_state(1)).unwrap(), PageState::Erased);
+    }
+
+    #[test]
+    fn program_unit_test() {
+        let mut crc32 = crc32_ethernet();
+        let mut desc_list = [
+            RecordDesc {
+                tag : 0,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 1,
+                offset : None,
+            },
+        ];
+
+        // 64, 128 and 256-bit flash words
+        for unit in [2, 4, 8] {
+            let mut storage = Storage::new(WideMem { mem : TestMem::<0x800>::with_layout(TEST_MIXED_SECTORS, !0), unit });
+            storage.set_verify(true);
+            storage.init(&mut desc_list, &mut crc32).unwrap();
+            assert_eq!(storage.record_len(3 * WORD_SIZE) % unit, 0);
+
+            let e1 = [0x7777_7777; 5];
+            storage.update(&mut desc_list[1], as_bytes(&e1), &mut crc32).unwrap();
+            // Records must fit the smallest page, that is 24 data words for 256-bit unit
+            for counter in 0 .. 300u32 {
+                let len = 1 + counter as usize % 8;
+                storage.update_or_compact(&mut desc_list, 0, as_bytes(&[counter; 8][.. len]), &mut crc32).unwrap();
+                assert_eq!(storage.current % unit, 0);
+            }
+
+            let stats = storage.init(&mut desc_list, &mut crc32).unwrap();
+            assert_eq!(stats.words_wasted, 0);
+            assert_eq!(stats.torn_records, 0);
+            assert_eq!(storage.get(&desc_list[0]).unwrap().unwrap(), as_bytes(&[299; 8][.. 1 + 299 % 8]));
+            assert_eq!(storage.get(&desc_list[1]).unwrap().unwrap(), as_bytes(&e1));
+        }
+    }
+
+    #[test]
+    fn zero_erased_test() {
         let mut crc32 = crc32_ethernet();
+        let mut desc_list = [
+            RecordDesc {
+                tag : 0,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 1,
+                offset : None,
+            },
+        ];
+
+        let mut storage = Storage::new(TestMem::<0x100>::with_layout(TEST_SECTORS, 0));
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert!(storage.is_empty().unwrap());
+        assert_eq!(ready(storage.page_state(1)).unwrap(), PageState::Erased);
+
+        // Zero payload words look erased, but belong to record
+        let e1 = [0, 0, 7];
+        storage.update(&mut desc_list[1], as_bytes(&e1), &mut crc32).unwrap();
+        for counter in 0 .. 100u32 {
+            storage.update_or_compact(&mut desc_list, 0, as_bytes(&[counter, 0]), &mut crc32).unwrap();
+        }
 
+        let current = storage.current;
+        let stats = storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(stats.words_wasted, 0);
+        assert_eq!(storage.current, current);
+        assert_eq!(storage.get(&desc_list[0]).unwrap().unwrap(), as_bytes(&[99, 0]));
+        assert_eq!(storage.get(&desc_list[1]).unwrap().unwrap(), as_bytes(&e1));
+
+        // Record without commit word is torn and its space is skipped
+        ready(storage.write_record(current, 0, Payload::Bytes(as_bytes(&[100, 0])), &mut crc32)).unwrap();
+        let stats = storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(stats.torn_records, 1);
+        assert_eq!(storage.current, current + storage.record_len(2 * WORD_SIZE));
+        assert_eq!(storage.get(&desc_list[0]).unwrap().unwrap(), as_bytes(&[99, 0]));
+    }
+
+    #[test]
+    fn byte_payload_test() {
+        let mut crc32 = crc32_ethernet();
         let mut desc_list = [
             RecordDesc {
                 tag : 0,
-                ptr : None,
+                offset : None,
             },
             RecordDesc {
                 tag : 1,
-                ptr : None,
+                offset : None,
             },
             RecordDesc {
                 tag : 2,
-                ptr : None,
+                offset : None,
             },
         ];
 
-        let e0 = [!42u32; 10];
-        storage.update(&mut desc_list[0], &e0, &mut crc32).unwrap();
+        let mut storage = new_storage();
+        storage.update(&mut desc_list[0], &[42], &mut crc32).unwrap();
+        storage.update(&mut desc_list[1], &[0, 1, 0, 1, 1], &mut crc32).unwrap();
+        storage.update(&mut desc_list[2], &[], &mut crc32).unwrap();
+        assert_eq!(storage.len().unwrap(), (3 * storage.record_len(0) + 1 + 2) * WORD_SIZE);
+
+        // Padding is not part of payload
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(storage.get(&desc_list[0]).unwrap().unwrap(), &[42]);
+        assert_eq!(storage.get(&desc_list[1]).unwrap().unwrap(), &[0, 1, 0, 1, 1]);
+        assert_eq!(storage.get(&desc_list[2]).unwrap().unwrap(), &[]);
+
+        // Byte payloads survive compaction
+        for counter in 0 .. 100u8 {
+            storage.update_or_compact(&mut desc_list, 0, &[counter; 7], &mut crc32).unwrap();
+        }
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(storage.get(&desc_list[0]).unwrap().unwrap(), &[99; 7]);
+        assert_eq!(storage.get(&desc_list[1]).unwrap().unwrap(), &[0, 1, 0, 1, 1]);
+    }
+
+    #[test]
+    fn compact_header_test() {
+        let mut crc32 = crc32_ethernet();
+        let mut desc_list = [
+            RecordDesc {
+                tag : 0,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 1,
+                offset : None,
+            },
+        ];
+
+        let mut storage = Storage::<_, CompactHeader>::with_format(TestMem::new());
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(storage.record_len(1), 4);
 
         let e1 = [0x7777_7777; 3];
-        storage.update(&mut desc_list[1], &e1, &mut crc32).unwrap();
+        storage.update(&mut desc_list[1], as_bytes(&e1), &mut crc32).unwrap();
+        for counter in 0 .. 200u8 {
+            storage.update_or_compact(&mut desc_list, 0, &[counter], &mut crc32).unwrap();
+        }
 
-        let e0 = [0x6666_6666; 3];
-        storage.update(&mut desc_list[0], &e0, &mut crc32).unwrap();
+        let stats = storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(stats.words_wasted, 0);
+        assert_eq!(storage.get(&desc_list[0]).unwrap().unwrap(), &[199]);
+        assert_eq!(storage.get(&desc_list[1]).unwrap().unwrap(), as_bytes(&e1));
 
-        let e2 = [0x5555_5555; 3];
-        storage.update(&mut desc_list[2], &e2, &mut crc32).unwrap();
+        // Damaged record is found by 16-bit checksum
+        let idx = desc_list[0].offset.unwrap();
+        storage.storage.0[idx + 2] ^= 1;
+        let stats = storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(stats.corrupted_records, 1);
+        assert_eq!(storage.get(&desc_list[0]).unwrap().unwrap(), &[198]);
 
-        let e0 = [0xA5B5A5A5u32; 2];
-        storage.update(&mut desc_list[0], &e0, &mut crc32).unwrap();
+        // Interrupted write leaves previous version
+        let snapshot = storage.storage.clone();
+        for ops_before_loss in 0 .. storage.record_len(1) {
+            let mut storage = Storage::<_, CompactHeader>::with_format(PowerLossMem { mem : snapshot.clone(), ops_before_loss });
+            let mut list = desc_list;
+            storage.init(&mut list, &mut crc32).unwrap();
+            storage.update(&mut list[0], &[200], &mut crc32).unwrap();
 
-        let e1 = [66u32; 5];
+            let mut storage = Storage::<_, CompactHeader>::with_format(storage.storage.mem);
+            storage.init(&mut list, &mut crc32).unwrap();
+            assert_eq!(storage.get(&list[0]).unwrap().unwrap(), &[198]);
+            storage.update(&mut list[0], &[201], &mut crc32).unwrap();
+            storage.init(&mut list, &mut crc32).unwrap();
+            assert_eq!(storage.get(&list[0]).unwrap().unwrap(), &[201]);
+        }
+
+        // Tags which don't fit header are refused
+        let mut map = TagMap::<2>::new();
+        map.insert(1).unwrap();
+        map.insert(0x1_0000).unwrap();
+        match storage.init(&mut map, &mut crc32) {
+            Err(Error::TagOutOfRange) => {}
+            res => panic!("{:?}", res),
+        }
+        let mut record = RecordDesc { tag : 0x1_0000, offset : None };
+        match storage.update(&mut record, &[1], &mut crc32) {
+            Err(Error::TagOutOfRange) => {}
+            res => panic!("{:?}", res),
+        }
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(storage.get(&desc_list[0]).unwrap().unwrap(), &[198]);
+
+        // Payload which size doesn't fit header is refused before anything
+        // is reclaimed
+        let mut storage = Storage::<_, ShortHeader>::with_format(TestMem::new());
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        storage.update(&mut desc_list[0], &[1; 8], &mut crc32).unwrap();
+        let snapshot = storage.storage.clone();
+        match storage.update_or_compact(&mut desc_list, 0, &[2; 9], &mut crc32) {
+            Err(Error::RecordTooLarge) => {}
+            res => panic!("{:?}", res),
+        }
+        assert_eq!(storage.storage.0[..], snapshot.0[..]);
+    }
+
+    #[test]
+    fn sparse_tags_test() {
+        let mut crc32 = crc32_ethernet();
+        let mut map = TagMap::<4>::new();
+        map.insert(0xC0DE_0001).unwrap();
+        map.insert(0x0100).unwrap();
+        map.insert(0xFFFF_FFF0).unwrap();
+
+        let mut storage = Storage::new(TestMem::new());
+        storage.init(&mut map, &mut crc32).unwrap();
+        storage.update(map.find_mut(0x0100).unwrap(), &[1, 2], &mut crc32).unwrap();
+        storage.update(map.find_mut(0xFFFF_FFF0).unwrap(), &[3], &mut crc32).unwrap();
+        for counter in 0 .. 100u8 {
+            storage.update_or_compact(&mut map, 0xC0DE_0001, &[counter; 5], &mut crc32).unwrap();
+        }
+
+        let stats = storage.init(&mut map, &mut crc32).unwrap();
+        assert_eq!(stats.unique_tags, 3);
+        assert_eq!(storage.get(map.find(0xC0DE_0001).unwrap()).unwrap().unwrap(), &[99; 5]);
+        assert_eq!(storage.get(map.find(0x0100).unwrap()).unwrap().unwrap(), &[1, 2]);
+        assert_eq!(storage.get(map.find(0xFFFF_FFF0).unwrap()).unwrap().unwrap(), &[3]);
+
+        // Component owning a part of tags sees only its own records
+        let mut list = [
+            RecordDesc {
+                tag : 0x0100,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 0x0200,
+                offset : None,
+            },
+        ];
+        let stats = storage.init(&mut list, &mut crc32).unwrap();
+        assert_eq!(stats.unique_tags, 1);
+        assert_eq!(storage.get(&list[0]).unwrap().unwrap(), &[1, 2]);
+        assert_eq!(storage.get(&list[1]).unwrap(), None);
+    }
+
+    #[test]
+    fn unknown_tags_test() {
+        let mut crc32 = crc32_ethernet();
+        let mut old_list = [
+            RecordDesc {
+                tag : 0,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 1,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 2,
+                offset : None,
+            },
+        ];
+
+        let mut storage = Storage::new(TestMem::new());
+        storage.init(&mut old_list, &mut crc32).unwrap();
+        storage.update(&mut old_list[0], &[1], &mut crc32).unwrap();
+        storage.update(&mut old_list[2], &[2, 2], &mut crc32).unwrap();
+        storage.update(&mut old_list[2], &[3, 3], &mut crc32).unwrap();
+
+        // Newer firmware has dropped tag 2 and moves its value to tag 1
+        let mut list = [old_list[0], old_list[1]];
+        let mut dropped = None;
+        let stats = storage.init_with(&mut list, &mut crc32, |record| dropped = Some(record)).unwrap();
+        assert_eq!(stats.unknown_records, 2);
+        assert_eq!(stats.unique_tags, 1);
+        let dropped = dropped.unwrap();
+        assert_eq!(dropped.tag, 2);
+        let value = storage.get(&dropped).unwrap().unwrap()[0];
+        storage.update(&mut list[1], &[value], &mut crc32).unwrap();
+
+        // Latest record of unknown tag is kept by compaction
+        for _ in 0 .. storage.pages_num() {
+            storage.compact(&mut list, &mut crc32).unwrap();
+        }
+        let stats = storage.init(&mut list, &mut crc32).unwrap();
+        assert_eq!(stats.unknown_records, 1);
+
+        // Records of dropped tag are gone once their page is reclaimed
+        storage.set_drop_unknown(true);
+        for _ in 0 .. storage.pages_num() {
+            storage.compact(&mut list, &mut crc32).unwrap();
+        }
+        let stats = storage.init(&mut list, &mut crc32).unwrap();
+        assert_eq!(stats.unknown_records, 0);
+        assert_eq!(storage.get(&list[0]).unwrap().unwrap(), &[1]);
+        assert_eq!(storage.get(&list[1]).unwrap().unwrap(), &[3]);
+    }
+
+    #[test]
+    fn shared_storage_test() {
+        let mut crc32 = crc32_ethernet();
+        // Components own their own tag ranges
+        let mut first = [
+            RecordDesc {
+                tag : 0x10,
+                offset : None,
+            },
+        ];
+        let mut second = [
+            RecordDesc {
+                tag : 0x20,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 0x21,
+                offset : None,
+            },
+        ];
+
+        let mut storage = Storage::new(TestMem::new());
+        storage.init(&mut second, &mut crc32).unwrap();
+        storage.update(&mut second[0], &[1], &mut crc32).unwrap();
+        storage.update(&mut second[1], &[2], &mut crc32).unwrap();
+        storage.update(&mut second[1], &[3], &mut crc32).unwrap();
+
+        // Compaction by the first component keeps records of the second one
+        storage.init(&mut first, &mut crc32).unwrap();
+        for counter in 0 .. 100u8 {
+            storage.update_or_compact(&mut first, 0x10, &[counter; 7], &mut crc32).unwrap();
+        }
+        let stats = storage.init(&mut second, &mut crc32).unwrap();
+        assert_eq!(stats.unique_tags, 2);
+        assert_eq!(storage.get(&second[0]).unwrap().unwrap(), &[1]);
+        assert_eq!(storage.get(&second[1]).unwrap().unwrap(), &[3]);
+        storage.init(&mut first, &mut crc32).unwrap();
+        assert_eq!(storage.get(&first[0]).unwrap().unwrap(), &[99; 7]);
+
+        // Slice which isn't sorted by tag can't be looked up
+        let mut unsorted = [second[1], second[0]];
+        match storage.init(&mut unsorted, &mut crc32) {
+            Err(Error::InconsistentTable) => {}
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn remove_test() {
+        let mut crc32 = crc32_ethernet();
+        let mut desc_list = [
+            RecordDesc {
+                tag : 0,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 1,
+                offset : None,
+            },
+        ];
+
+        let mut storage = Storage::new(TestMem::new());
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        storage.update(&mut desc_list[0], &[1, 2, 3], &mut crc32).unwrap();
+        storage.update(&mut desc_list[1], &[4], &mut crc32).unwrap();
+        storage.remove(&mut desc_list[0], &mut crc32).unwrap();
+        assert_eq!(desc_list[0].offset, None);
+
+        // Missing record takes no tombstone
+        let len = storage.len().unwrap();
+        storage.remove(&mut desc_list[0], &mut crc32).unwrap();
+        assert_eq!(storage.len().unwrap(), len);
+
+        let stats = storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(stats.unique_tags, 1);
+        assert_eq!(storage.get(&desc_list[0]).unwrap(), None);
+        assert_eq!(storage.get(&desc_list[1]).unwrap().unwrap(), &[4]);
+
+        // Interrupted tombstone leaves record in place
+        storage.update(&mut desc_list[0], &[5], &mut crc32).unwrap();
+        let snapshot = storage.storage.clone();
+        for ops_before_loss in 0 .. storage.record_len(0) {
+            let mut storage = Storage::new(PowerLossMem { mem : snapshot.clone(), ops_before_loss });
+            let mut list = desc_list;
+            storage.init(&mut list, &mut crc32).unwrap();
+            storage.remove(&mut list[0], &mut crc32).unwrap();
+
+            let mut storage = Storage::new(storage.storage.mem);
+            storage.init(&mut list, &mut crc32).unwrap();
+            assert_eq!(storage.get(&list[0]).unwrap().unwrap(), &[5]);
+        }
+
+        // Removed record isn't brought back by compaction
+        storage.remove_or_compact(&mut desc_list, 0, &mut crc32).unwrap();
+        for counter in 0 .. 100u8 {
+            storage.update_or_compact(&mut desc_list, 1, &[counter; 6], &mut crc32).unwrap();
+        }
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(storage.get(&desc_list[0]).unwrap(), None);
+        assert_eq!(storage.get(&desc_list[1]).unwrap().unwrap(), &[99; 6]);
+    }
+
+    #[test]
+    fn unchanged_update_test() {
+        let mut crc32 = crc32_ethernet();
+        let mut desc_list = [
+            RecordDesc {
+                tag : 0,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 1,
+                offset : None,
+            },
+        ];
+
+        let mut storage = Storage::new(TestMem::new());
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        storage.update(&mut desc_list[0], &[1, 2, 3], &mut crc32).unwrap();
+        storage.update(&mut desc_list[1], &[1, 2, 3], &mut crc32).unwrap();
+        let len = storage.len().unwrap();
+
+        storage.update(&mut desc_list[0], &[1, 2, 3], &mut crc32).unwrap();
+        storage.update_or_compact(&mut desc_list, 1, &[1, 2, 3], &mut crc32).unwrap();
+        assert_eq!(storage.len().unwrap(), len);
+        assert_eq!(storage.skipped_writes(), 2);
+
+        // Payload of other length or content is written
+        storage.update(&mut desc_list[0], &[1, 2], &mut crc32).unwrap();
+        storage.update(&mut desc_list[1], &[1, 2, 4], &mut crc32).unwrap();
+        storage.update(&mut desc_list[0], &[1, 2, 3], &mut crc32).unwrap();
+        assert_eq!(storage.skipped_writes(), 2);
+        let stats = storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(stats.skipped_writes, 2);
+        assert_eq!(storage.get(&desc_list[0]).unwrap().unwrap(), &[1, 2, 3]);
+        assert_eq!(storage.get(&desc_list[1]).unwrap().unwrap(), &[1, 2, 4]);
+
+        // Removed record is written again
+        storage.remove(&mut desc_list[1], &mut crc32).unwrap();
+        storage.update(&mut desc_list[1], &[1, 2, 4], &mut crc32).unwrap();
+        assert_eq!(storage.skipped_writes(), 2);
+        assert_eq!(storage.get(&desc_list[1]).unwrap().unwrap(), &[1, 2, 4]);
+    }
+
+    #[test]
+    fn external_mem_test() {
+        let mut crc32 = crc32_ethernet();
+        let mut desc_list = [
+            RecordDesc {
+                tag : 0,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 1,
+                offset : None,
+            },
+        ];
+        let mut mapped_list = desc_list;
+
+        let mut mapped = Storage::new(TestMem::new());
+        let mut external = Storage::new(ExternalMem { mem : TestMem::new() });
+        mapped.init(&mut mapped_list, &mut crc32).unwrap();
+        external.init(&mut desc_list, &mut crc32).unwrap();
+
+        // Payload takes more than one read chunk
+        let mut e1 = [0u8; 80];
+        for (n, byte) in e1.iter_mut().enumerate() {
+            *byte = n as u8;
+        }
+        mapped.update(&mut mapped_list[1], &e1, &mut crc32).unwrap();
+        external.update(&mut desc_list[1], &e1, &mut crc32).unwrap();
+        for counter in 0 .. 20u8 {
+            mapped.update_or_compact(&mut mapped_list, 0, &[counter; 5], &mut crc32).unwrap();
+            external.update_or_compact(&mut desc_list, 0, &[counter; 5], &mut crc32).unwrap();
+        }
+
+        // Record format doesn't depend on read mode
+        assert_eq!(mapped.storage.0, external.storage.mem.0);
+
+        let stats = external.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(stats.unique_tags, 2);
+        assert_eq!(stats.corrupted_records, 0);
+        let mut buf = [0; 80];
+        assert_eq!(external.get_into(&desc_list[0], &mut buf).unwrap(), Some(5));
+        assert_eq!(&buf[.. 5], &[19; 5]);
+        assert_eq!(external.get_into(&desc_list[1], &mut buf).unwrap(), Some(80));
+        assert_eq!(buf, e1);
+        assert!(matches!(external.get_into(&desc_list[1], &mut buf[.. 79]), Err(Error::BufferTooSmall)));
+
+        // Damage in the last chunk of payload is found by streamed checksum
+        let idx = desc_list[1].offset.unwrap() + HEADER_LEN + 19;
+        external.storage.mem.0[idx] ^= 1;
+        let stats = external.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(stats.corrupted_records, 1);
+        assert_eq!(external.get_into(&desc_list[1], &mut buf).unwrap(), None);
+    }
+
+    fn word_type_roundtrip<W : Word>(erased : W, program_unit : usize) {
+        let mut crc32 = Digest::new_custom(IEEE, !0u32, 0u32, CalcType::Normal);
+        let mut desc_list = [
+            RecordDesc {
+                tag : 0,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 1,
+                offset : None,
+            },
+        ];
+
+        let mut storage = Storage::new(ArrayMem { mem : [erased; 0x400], erased, program_unit });
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(storage.capacity().unwrap(), 0x400 * size_of::<W>());
+        assert_eq!(storage.record_len(3) % program_unit, 0);
+
+        let e1 = [1, 2, 3, 4, 5];
         storage.update(&mut desc_list[1], &e1, &mut crc32).unwrap();
-        
-        storage.init(&mut desc_list, &mut crc32);
-        assert_eq!(storage.get(&desc_list[0]).unwrap().unwrap(), &e0);
+        for counter in 0 .. 200u8 {
+            storage.update_or_compact(&mut desc_list, 0, &[counter; 3], &mut crc32).unwrap();
+        }
+
+        let stats = storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(stats.words_wasted, 0);
+        assert_eq!(stats.torn_records, 0);
+        assert_eq!(storage.get(&desc_list[0]).unwrap().unwrap(), &[199; 3]);
         assert_eq!(storage.get(&desc_list[1]).unwrap().unwrap(), &e1);
-        assert_eq!(storage.get(&desc_list[2]).unwrap().unwrap(), &e2);
-        
-        //println!("Desc list : {:#?}", &desc_list);
+    }
+
+    #[test]
+    fn word_type_test() {
+        // Byte writable EEPROM
+        word_type_roundtrip(0xFFu8, 1);
+        // 16-bit parallel flash, erased to ones and to zeroes
+        word_type_roundtrip(0xFFFFu16, 1);
+        word_type_roundtrip(0u16, 2);
+        // 64-bit words, programmed by one word and by 256-bit flash words
+        word_type_roundtrip(!0u64, 1);
+        word_type_roundtrip(!0u64, 4);
+        // 128 and 256-bit flash words addressed by bytes and half words
+        word_type_roundtrip(0xFFu8, 16);
+        word_type_roundtrip(0xFFFFu16, 16);
     }
 
     #[test]
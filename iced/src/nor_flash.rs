@@ -0,0 +1,313 @@
+//! Storage memory on top of `embedded-storage` NOR flash
+
+use core::cell::RefCell;
+use core::marker::PhantomData;
+use core::mem::{size_of, size_of_val};
+
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash, ErrorType, NorFlashErrorKind};
+
+use crate::{StorageMem, Geometry, SectorRegion, Word, MAX_PROGRAM_UNIT_SIZE};
+
+/// Bytes read from flash at once
+pub(crate) const READ_BUF_LEN : usize = 0x40;
+
+/// Erased NOR flash reads as ones
+pub(crate) const ERASED_BYTE : u8 = 0xFF;
+
+/// Adapter of `embedded-storage` NOR flash to `StorageMem`.
+///
+/// Storage takes region of flash starting at byte `start` and laid out in
+/// `sectors`, sector size is in words of `W` and is a multiple of
+/// `NorFlash::ERASE_SIZE`. `NorFlash::WRITE_SIZE` becomes program unit.
+pub struct NorFlashMem<F : ErrorType, W = u32> {
+    pub(crate) flash : RefCell<F>,
+    start   : u32,
+    sectors : &'static [SectorRegion],
+    word    : PhantomData<W>,
+}
+
+impl<F : NorFlash> NorFlashMem<F> {
+
+    pub fn new(flash : F, start : u32, sectors : &'static [SectorRegion]) -> Self {
+        Self::with_word(flash, start, sectors)
+    }
+}
+
+impl<F : NorFlash, W : Word> NorFlashMem<F, W> {
+
+    /// Flash addressed by words of given type
+    pub fn with_word(flash : F, start : u32, sectors : &'static [SectorRegion]) -> Self {
+        let capacity = flash.capacity();
+        Self::with_layout(flash, [F::READ_SIZE, F::WRITE_SIZE, F::ERASE_SIZE], capacity, start, sectors)
+    }
+}
+
+// Parts shared with `AsyncNorFlashMem`
+impl<F : ErrorType, W : Word> NorFlashMem<F, W> {
+
+    /// Flash of given read, write and erase sizes and capacity
+    pub(crate) fn with_layout(flash : F, sizes : [usize; 3], capacity : usize, start : u32, sectors : &'static [SectorRegion]) -> Self {
+        check_layout::<W>(sizes, capacity, start, sectors);
+        Self {
+            flash   : RefCell::new(flash),
+            start,
+            sectors,
+            word    : PhantomData,
+        }
+    }
+
+    /// Give flash back
+    pub fn release(self) -> F {
+        self.flash.into_inner()
+    }
+
+    fn address(&self, offset_words : usize) -> u32 {
+        self.start + (offset_words * size_of::<W>()) as u32
+    }
+
+    /// Address and bytes of unit to program
+    pub(crate) fn unit_bytes<'a>(&self, offset_words : usize, words : &[W], buf : &'a mut [u8; MAX_PROGRAM_UNIT_SIZE]) -> (u32, &'a [u8]) {
+        let bytes = &mut buf[.. size_of_val(words)];
+        for (word, chunk) in words.iter().zip(bytes.chunks_mut(size_of::<W>())) {
+            word.to_bytes(chunk);
+        }
+        (self.address(offset_words), bytes)
+    }
+
+    /// Address and words of every chunk read at once
+    pub(crate) fn read_chunks<'a>(&'a self, offset_words : usize, words : &'a mut [W]) -> impl Iterator<Item = (u32, &'a mut [W])> + 'a {
+        let chunk_len = READ_BUF_LEN / size_of::<W>();
+        words.chunks_mut(chunk_len).enumerate().map(move |(n, chunk)| (self.address(offset_words + n * chunk_len), chunk))
+    }
+
+    /// Store bytes read to words
+    pub(crate) fn read_done(&self, bytes : &[u8], words : &mut [W]) {
+        for (word, src) in words.iter_mut().zip(bytes.chunks(size_of::<W>())) {
+            *word = W::from_bytes(src);
+        }
+    }
+
+    /// Address range of sector
+    pub(crate) fn sector_range(&self, sector : usize) -> (u32, u32) {
+        let geometry = self.flash_geometry(1);
+        let from = self.address(geometry.sector_start(sector));
+        (from, from + (geometry.sector_len(sector) * size_of::<W>()) as u32)
+    }
+
+    /// Geometry of flash of given write size
+    pub(crate) fn flash_geometry(&self, write_size : usize) -> Geometry<W> {
+        Geometry {
+            sectors      : self.sectors,
+            program_unit : (write_size / size_of::<W>()).max(1),
+            erased       : W::from_bytes(&[ERASED_BYTE; size_of::<u64>()][.. size_of::<W>()]),
+        }
+    }
+
+    /// Storage size in words
+    pub(crate) fn flash_len(&self) -> usize {
+        sectors_len(self.sectors)
+    }
+}
+
+impl<F : NorFlash, W : Word> StorageMem for NorFlashMem<F, W> {
+    type Word = W;
+    type Error = F::Error;
+
+    fn write(&mut self, offset_words : usize, words : &[W]) -> Result<(), Self::Error> {
+        let mut buf = [0; MAX_PROGRAM_UNIT_SIZE];
+        let (address, bytes) = self.unit_bytes(offset_words, words, &mut buf);
+        self.flash.get_mut().write(address, bytes)
+    }
+
+    fn read(&self, offset_words : usize) -> Result<W, Self::Error> {
+        let mut word = [W::default()];
+        self.read_into(offset_words, &mut word)?;
+        Ok(word[0])
+    }
+
+    fn read_into(&self, offset_words : usize, words : &mut [W]) -> Result<(), Self::Error> {
+        let mut buf = [0; READ_BUF_LEN];
+        for (address, chunk) in self.read_chunks(offset_words, words) {
+            let bytes = &mut buf[.. size_of_val(chunk)];
+            self.flash.borrow_mut().read(address, bytes)?;
+            self.read_done(bytes, chunk);
+        }
+        Ok(())
+    }
+
+    fn erase(&mut self, sector : usize) -> Result<(), Self::Error> {
+        let (from, to) = self.sector_range(sector);
+        self.flash.get_mut().erase(from, to)
+    }
+
+    fn geometry(&self) -> Geometry<W> {
+        self.flash_geometry(F::WRITE_SIZE)
+    }
+
+    fn len(&self) -> usize {
+        self.flash_len()
+    }
+}
+
+/// Check storage of given sectors at byte `start` fits flash of given read,
+/// write and erase sizes and capacity
+fn check_layout<W>([read_size, write_size, erase_size] : [usize; 3], capacity : usize, start : u32, sectors : &[SectorRegion]) {
+    let word_size = size_of::<W>();
+    assert!(word_size.is_multiple_of(read_size), "Word can't be read alone");
+    assert!(word_size.is_multiple_of(write_size) || write_size.is_multiple_of(word_size), "Write size doesn't match words");
+    assert!(write_size <= MAX_PROGRAM_UNIT_SIZE, "Unsupported write size");
+    assert!((start as usize).is_multiple_of(erase_size), "Storage start isn't erase aligned");
+    for region in sectors {
+        assert!((region.sector_len * word_size).is_multiple_of(erase_size), "Sector isn't erase aligned");
+    }
+    assert!(start as usize + sectors_len(sectors) * word_size <= capacity, "Storage doesn't fit flash");
+}
+
+/// Size of sectors in words
+fn sectors_len(sectors : &[SectorRegion]) -> usize {
+    sectors.iter().map(|region| region.count * region.sector_len).sum()
+}
+
+/// RAM flash of `N` bytes with 8 byte writes and 256 byte sectors
+#[cfg(any(test, feature="test-def"))]
+#[derive(Clone)]
+pub struct MockFlash<const N : usize> {
+    pub mem        : [u8; N],
+    /// Every read fails while set
+    pub fail_reads : bool,
+}
+
+#[cfg(any(test, feature="test-def"))]
+impl<const N : usize> MockFlash<N> {
+    pub fn new() -> Self {
+        Self { mem : [ERASED_BYTE; N], fail_reads : false }
+    }
+
+    pub(crate) fn check(&self, offset : u32, len : usize, align : usize) -> Result<core::ops::Range<usize>, NorFlashErrorKind> {
+        let offset = offset as usize;
+        if offset + len > N {
+            return Err(NorFlashErrorKind::OutOfBounds);
+        }
+        if !offset.is_multiple_of(align) || !len.is_multiple_of(align) {
+            return Err(NorFlashErrorKind::NotAligned);
+        }
+        Ok(offset .. offset + len)
+    }
+}
+
+#[cfg(any(test, feature="test-def"))]
+impl<const N : usize> Default for MockFlash<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(any(test, feature="test-def"))]
+impl<const N : usize> ErrorType for MockFlash<N> {
+    type Error = NorFlashErrorKind;
+}
+
+#[cfg(any(test, feature="test-def"))]
+impl<const N : usize> ReadNorFlash for MockFlash<N> {
+    const READ_SIZE : usize = 1;
+
+    fn read(&mut self, offset : u32, bytes : &mut [u8]) -> Result<(), Self::Error> {
+        if self.fail_reads {
+            return Err(NorFlashErrorKind::Other);
+        }
+        let range = self.check(offset, bytes.len(), Self::READ_SIZE)?;
+        bytes.copy_from_slice(&self.mem[range]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        N
+    }
+}
+
+#[cfg(any(test, feature="test-def"))]
+impl<const N : usize> NorFlash for MockFlash<N> {
+    const WRITE_SIZE : usize = 8;
+    const ERASE_SIZE : usize = 0x100;
+
+    fn erase(&mut self, from : u32, to : u32) -> Result<(), Self::Error> {
+        if from > to {
+            return Err(NorFlashErrorKind::OutOfBounds);
+        }
+        let range = self.check(from, (to - from) as usize, Self::ERASE_SIZE)?;
+        self.mem[range].fill(ERASED_BYTE);
+        Ok(())
+    }
+
+    fn write(&mut self, offset : u32, bytes : &[u8]) -> Result<(), Self::Error> {
+        let range = self.check(offset, bytes.len(), Self::WRITE_SIZE)?;
+        assert!(self.mem[range.clone()].iter().all(|&byte| byte == ERASED_BYTE), "Flash at {} is programmed twice", offset);
+        self.mem[range].copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Storage, RecordDesc, Error};
+    use crc::crc32::{Digest, IEEE};
+    use crc::CalcType;
+
+    // Storage takes 0x400 bytes after the first sector of flash
+    const SECTORS : &[SectorRegion] = &[
+        SectorRegion { count : 4, sector_len : 0x40 },
+    ];
+
+    #[test]
+    fn nor_flash_test() {
+        let mut crc32 = Digest::new_custom(IEEE, !0u32, 0u32, CalcType::Normal);
+        let mut desc_list = [
+            RecordDesc {
+                tag : 0,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 1,
+                offset : None,
+            },
+        ];
+
+        let mut flash = MockFlash::<0x800>::new();
+        flash.mem[.. 0x100].fill(0);
+        flash.mem[0x500 ..].fill(0);
+
+        let mut storage = Storage::new(NorFlashMem::new(flash, 0x100, SECTORS));
+        assert_eq!(storage.storage.geometry().program_unit, 2);
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        storage.update(&mut desc_list[1], &[1, 2, 3], &mut crc32).unwrap();
+        for counter in 0 .. 100u8 {
+            storage.update_or_compact(&mut desc_list, 0, &[counter; 9], &mut crc32).unwrap();
+        }
+
+        // Flash outside of storage is intact
+        let flash = storage.storage.release();
+        assert!(flash.mem[.. 0x100].iter().chain(&flash.mem[0x500 ..]).all(|&byte| byte == 0));
+
+        let mut storage = Storage::new(NorFlashMem::new(flash, 0x100, SECTORS));
+        let stats = storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(stats.unique_tags, 2);
+        assert_eq!(stats.words_wasted, 0);
+        let mut buf = [0; 9];
+        assert_eq!(storage.get_into(&desc_list[0], &mut buf).unwrap(), Some(9));
+        assert_eq!(buf, [99; 9]);
+        assert_eq!(storage.get_into(&desc_list[1], &mut buf).unwrap(), Some(3));
+        assert_eq!(&buf[.. 3], &[1, 2, 3]);
+
+        // Failed read is reported by the operation which reads
+        storage.storage.flash.get_mut().fail_reads = true;
+        assert!(matches!(storage.get_into(&desc_list[1], &mut buf), Err(Error::Mem(NorFlashErrorKind::Other))));
+        assert!(matches!(storage.update(&mut desc_list[1], &[4], &mut crc32), Err(Error::Mem(NorFlashErrorKind::Other))));
+        assert!(matches!(storage.compact(&mut desc_list, &mut crc32), Err(Error::Mem(NorFlashErrorKind::Other))));
+        assert!(matches!(storage.init(&mut desc_list, &mut crc32), Err(Error::Mem(NorFlashErrorKind::Other))));
+        storage.storage.flash.get_mut().fail_reads = false;
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        storage.update(&mut desc_list[1], &[4], &mut crc32).unwrap();
+        assert_eq!(storage.get_into(&desc_list[1], &mut buf).unwrap(), Some(1));
+    }
+}
@@ -0,0 +1,371 @@
+//! Walk over records in log order
+
+use crate::{Storage, Mem, MappedMem, HeaderFormat, StorageHasher32, RecordIndex, RecordState, PageState, Error, ready};
+
+/// Valid record found in storage log
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct LogRecord<'a> {
+    pub tag     : u32,
+    /// Position of record header in storage memory, in words
+    pub offset  : usize,
+    /// Record len in words, header and padding included
+    pub len     : usize,
+    pub payload : &'a [u8],
+    /// Record holds the current value of its tag
+    pub latest  : bool,
+}
+
+// Position in log: page number counted from the oldest page and word index
+pub(crate) type LogPos = (usize, usize);
+
+#[allow(private_bounds)]
+impl<S : Mem, F : HeaderFormat> Storage<S, F> {
+
+    /// The oldest page and position of its first record
+    async fn log_start(&self) -> Result<(usize, LogPos),Error<S::Error>> {
+        let oldest = self.oldest_page().await?;
+        Ok((oldest, (0, self.data_start(oldest))))
+    }
+
+    /// Next valid record, tombstone or transaction commit at or after `pos`,
+    /// `pos` is moved past it. Pages are walked the same way as by `init`.
+    pub(crate) async fn next_valid(&self, oldest : usize, pos : &mut LogPos, hasher : &mut impl StorageHasher32<S::Word>) -> Result<Option<(usize, RecordState)>,Error<S::Error>> {
+        let pages_num = self.pages_num();
+        while pos.0 < pages_num {
+            let page = (oldest + pos.0) % pages_num;
+            let page_end = self.page_end(page);
+            if self.page_state(page).await? == PageState::Active {
+                while pos.1 + self.record_len(0) <= page_end {
+                    let idx = pos.1;
+                    let state = self.scan_record(idx, page_end, hasher).await?;
+                    match state {
+                        RecordState::Committed(_, len) | RecordState::Removed(_, len) |
+                        RecordState::Staged(_, len) | RecordState::TxCommit(_, len) => {
+                            pos.1 += len;
+                            return Ok(Some((idx, state)));
+                        }
+                        RecordState::Torn(len) | RecordState::Corrupted(len) | RecordState::Discarded(len) => {
+                            pos.1 += len;
+                        }
+                        RecordState::Erased | RecordState::Garbage => {
+                            pos.1 = self.resync(idx + self.unit(), page_end).await?;
+                        }
+                    }
+                }
+            }
+            pos.0 += 1;
+            pos.1 = self.data_start((oldest + pos.0) % pages_num);
+        }
+        Ok(None)
+    }
+
+    /// Staged record ending at `pos` belongs to committed transaction, the
+    /// same way `init` counts it: commit applies the last records of the run
+    pub(crate) async fn is_committed(&self, oldest : usize, pos : LogPos, hasher : &mut impl StorageHasher32<S::Word>) -> Result<bool,Error<S::Error>> {
+        let (staged, count) = self.staged_run(oldest, pos, hasher).await?;
+        Ok(staged <= count)
+    }
+
+    /// Same as `is_committed` for staged records walked one after another:
+    /// `run` keeps staged records left in the run and the number of them
+    /// committed, so every run is walked once
+    async fn is_run_committed(&self, oldest : usize, pos : LogPos, run : &mut Option<(usize, usize)>, hasher : &mut impl StorageHasher32<S::Word>) -> Result<bool,Error<S::Error>> {
+        let (staged, count) = match *run {
+            Some(run) => run,
+            None => self.staged_run(oldest, pos, hasher).await?,
+        };
+        *run = if staged > 1 { Some((staged - 1, count)) } else { None };
+        Ok(staged <= count)
+    }
+
+    /// Number of staged records from the one ending at `pos` to the end of
+    /// their run and number of records applied by commit following the run
+    async fn staged_run(&self, oldest : usize, mut pos : LogPos, hasher : &mut impl StorageHasher32<S::Word>) -> Result<(usize, usize),Error<S::Error>> {
+        let mut staged = 1;
+        while let Some((_, state)) = self.next_valid(oldest, &mut pos, hasher).await? {
+            match state {
+                RecordState::Staged(..) => staged += 1,
+                RecordState::TxCommit(count, _) => return Ok((staged, count as usize)),
+                _ => break,
+            }
+        }
+        Ok((staged, 0))
+    }
+
+    /// No record or tombstone of `tag` follows `pos`
+    pub(crate) async fn is_latest(&self, oldest : usize, mut pos : LogPos, tag : u32, hasher : &mut impl StorageHasher32<S::Word>) -> Result<bool,Error<S::Error>> {
+        while let Some((_, state)) = self.next_valid(oldest, &mut pos, hasher).await? {
+            match state {
+                RecordState::Committed(other, _) | RecordState::Removed(other, _) if other == tag => return Ok(false),
+                RecordState::Staged(other, _) if other == tag && self.is_committed(oldest, pos, hasher).await? => return Ok(false),
+                _ => {}
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl<S : MappedMem, F : HeaderFormat> Storage<S, F> {
+
+    /// Every valid record in log order, the oldest first. Tombstones and
+    /// records of transactions which were never committed are skipped.
+    /// Record is the latest one when `list`, the table kept by `init`, points
+    /// to it. Record of tag missing in `list` is checked by walking the rest
+    /// of the log, so such records are meant for diagnostics. Walk stops at
+    /// the first failed read.
+    pub fn records<'a, L : RecordIndex + ?Sized, H : StorageHasher32<S::Word>>(&'a self, list : &'a L, hasher : &'a mut H) -> Result<Records<'a, S, F, L, H>,Error<S::Error>> {
+        let (oldest, pos) = ready(self.log_start())?;
+        Ok(Records { log : self, list, hasher, oldest, pos, run : None })
+    }
+
+    /// Every stored version of record with given tag, the newest first.
+    /// Pages are searched from the head back, every version costs a scan of
+    /// its page.
+    pub fn history<'a, H : StorageHasher32<S::Word>>(&'a self, tag : u32, hasher : &'a mut H) -> Result<History<'a, S, F, H>,Error<S::Error>> {
+        let (oldest, _) = ready(self.log_start())?;
+        Ok(History { log : self, hasher, oldest, tag, pages_left : self.pages_num(), bound : None, latest : true })
+    }
+
+    fn log_record(&self, offset : usize, tag : u32, len : usize, latest : bool) -> Result<LogRecord<'_>,Error<S::Error>> {
+        let sz = ready(self.header_fields(offset))?.sz as usize;
+        let payload = self.payload_slice(offset + self.header_len(), sz);
+        Ok(LogRecord { tag, offset, len, payload, latest })
+    }
+}
+
+/// Iterator over valid records of storage log, see `Storage::records`
+pub struct Records<'a, S, F, L : ?Sized, H> {
+    log    : &'a Storage<S, F>,
+    list   : &'a L,
+    hasher : &'a mut H,
+    oldest : usize,
+    pos    : LogPos,
+    // Staged records left in the current run and number of them committed
+    run    : Option<(usize, usize)>,
+}
+
+impl<'a, S : MappedMem, F : HeaderFormat, L : RecordIndex + ?Sized, H : StorageHasher32<S::Word>> Records<'a, S, F, L, H> {
+
+    fn next_record(&mut self) -> Result<Option<LogRecord<'a>>,Error<S::Error>> {
+        while let Some((offset, state)) = ready(self.log.next_valid(self.oldest, &mut self.pos, self.hasher))? {
+            let (tag, len) = match state {
+                RecordState::Committed(tag, len) => (tag, len),
+                RecordState::Staged(tag, len) if ready(self.log.is_run_committed(self.oldest, self.pos, &mut self.run, self.hasher))? => (tag, len),
+                _ => continue,
+            };
+            let latest = match self.list.find(tag) {
+                Some(record) => record.offset == Some(offset),
+                None => ready(self.log.is_latest(self.oldest, self.pos, tag, self.hasher))?,
+            };
+            return self.log.log_record(offset, tag, len, latest).map(Some);
+        }
+        Ok(None)
+    }
+}
+
+impl<'a, S : MappedMem, F : HeaderFormat, L : RecordIndex + ?Sized, H : StorageHasher32<S::Word>> Iterator for Records<'a, S, F, L, H> {
+    type Item = Result<LogRecord<'a>,Error<S::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let res = self.next_record();
+        if res.is_err() {
+            // Nothing is given after error
+            self.pos.0 = self.log.pages_num();
+        }
+        res.transpose()
+    }
+}
+
+/// Iterator over versions of a record, see `Storage::history`
+pub struct History<'a, S, F, H> {
+    log        : &'a Storage<S, F>,
+    hasher     : &'a mut H,
+    oldest     : usize,
+    tag        : u32,
+    // Pages not searched through yet, counted from the oldest one. The last
+    // of them is searched for versions before `bound`.
+    pages_left : usize,
+    bound      : Option<usize>,
+    // Neither version nor tombstone of tag is found yet
+    latest     : bool,
+}
+
+impl<'a, S : MappedMem, F : HeaderFormat, H : StorageHasher32<S::Word>> History<'a, S, F, H> {
+
+    fn next_version(&mut self) -> Result<Option<LogRecord<'a>>,Error<S::Error>> {
+        while self.pages_left > 0 {
+            let n = self.pages_left - 1;
+            let page = (self.oldest + n) % self.log.pages_num();
+            // Last version or tombstone in page before bound, tombstone has
+            // no len
+            let mut found = None;
+            if ready(self.log.page_state(page))? == PageState::Active {
+                let mut pos = (n, self.log.data_start(page));
+                let mut run = None;
+                while let Some((offset, state)) = ready(self.log.next_valid(self.oldest, &mut pos, self.hasher))? {
+                    if pos.0 != n || self.bound.is_some_and(|bound| offset >= bound) {
+                        break;
+                    }
+                    match state {
+                        RecordState::Committed(tag, len) if tag == self.tag => found = Some((offset, Some(len))),
+                        RecordState::Removed(tag, _) if tag == self.tag => found = Some((offset, None)),
+                        // Every staged record counts down its run
+                        RecordState::Staged(tag, len) if ready(self.log.is_run_committed(self.oldest, pos, &mut run, self.hasher))? && tag == self.tag => {
+                            found = Some((offset, Some(len)));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
+            match found {
+                Some((offset, len)) => {
+                    let latest = self.latest;
+                    self.latest = false;
+                    self.bound = Some(offset);
+                    // Versions before tombstone are searched for in the same page
+                    if let Some(len) = len {
+                        return self.log.log_record(offset, self.tag, len, latest).map(Some);
+                    }
+                }
+                None => {
+                    self.pages_left -= 1;
+                    self.bound = None;
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<'a, S : MappedMem, F : HeaderFormat, H : StorageHasher32<S::Word>> Iterator for History<'a, S, F, H> {
+    type Item = Result<LogRecord<'a>,Error<S::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let res = self.next_version();
+        if res.is_err() {
+            // Nothing is given after error
+            self.pages_left = 0;
+        }
+        res.transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TestMem, RecordDesc};
+    use crc::crc32::{Digest, IEEE};
+    use crc::CalcType;
+
+    #[test]
+    fn records_test() {
+        let mut crc32 = Digest::new_custom(IEEE, !0u32, 0u32, CalcType::Normal);
+        let mut desc_list = [
+            RecordDesc {
+                tag : 0,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 1,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 2,
+                offset : None,
+            },
+        ];
+
+        let mut storage = Storage::new(TestMem::new());
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        storage.update(&mut desc_list[0], &[1], &mut crc32).unwrap();
+        storage.update(&mut desc_list[1], &[2, 2], &mut crc32).unwrap();
+        storage.update(&mut desc_list[0], &[3], &mut crc32).unwrap();
+        storage.update(&mut desc_list[2], &[4], &mut crc32).unwrap();
+        storage.remove(&mut desc_list[2], &mut crc32).unwrap();
+
+        let mut records = storage.records(&desc_list, &mut crc32).unwrap();
+        let record = records.next().unwrap().unwrap();
+        assert_eq!((record.tag, record.payload, record.latest), (0, &[1][..], false));
+        assert_eq!(record.len, storage.record_len(1));
+        let record = records.next().unwrap().unwrap();
+        assert_eq!((record.tag, record.payload, record.latest), (1, &[2, 2][..], true));
+        assert_eq!(record.offset, desc_list[1].offset.unwrap());
+        let record = records.next().unwrap().unwrap();
+        assert_eq!((record.tag, record.payload, record.latest), (0, &[3][..], true));
+        // Removed record is not the latest one, tombstone is not given
+        let record = records.next().unwrap().unwrap();
+        assert_eq!((record.tag, record.payload, record.latest), (2, &[4][..], false));
+        assert!(records.next().is_none());
+
+        // Records of tags missing in table are checked against the rest of log
+        let latest = storage.records(&desc_list[1 ..], &mut crc32).unwrap().map(|record| record.unwrap().latest);
+        assert!(latest.eq([false, true, true, false]));
+
+        // History goes over page boundaries, compacted copies included
+        for counter in 0 .. 40u8 {
+            storage.update_or_compact(&mut desc_list, 0, &[counter; 5], &mut crc32).unwrap();
+        }
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        let mut newer = 40;
+        for record in storage.history(0, &mut crc32).unwrap() {
+            let record = record.unwrap();
+            assert!(record.payload[0] < newer);
+            newer = record.payload[0];
+        }
+        let mut history = storage.history(0, &mut crc32).unwrap();
+        let record = history.next().unwrap().unwrap();
+        assert!(record.latest);
+        assert_eq!(Some(record.offset), desc_list[0].offset);
+        assert!(!history.next().unwrap().unwrap().latest);
+
+        // Compacted record is still the latest one
+        let record = storage.history(1, &mut crc32).unwrap().next().unwrap().unwrap();
+        assert_eq!((record.payload, record.latest), (&[2, 2][..], true));
+        assert_eq!(storage.records(&desc_list, &mut crc32).unwrap().filter(|record| record.as_ref().unwrap().latest).count(), 2);
+    }
+
+    #[test]
+    fn abandoned_transaction_test() {
+        let mut crc32 = Digest::new_custom(IEEE, !0u32, 0u32, CalcType::Normal);
+        let mut desc_list = [
+            RecordDesc {
+                tag : 0,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 1,
+                offset : None,
+            },
+        ];
+
+        let mut storage = Storage::new(TestMem::new());
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        storage.update(&mut desc_list[0], &[1], &mut crc32).unwrap();
+        {
+            let mut tx = storage.begin(&mut desc_list, &mut crc32);
+            tx.update(0, &[2]).unwrap();
+        }
+        storage.update(&mut desc_list[1], &[1], &mut crc32).unwrap();
+
+        // Record of transaction never committed is no version at all
+        let payloads = [(0, &[1][..], true), (1, &[1][..], true)];
+        assert!(storage.records(&desc_list, &mut crc32).unwrap().map(|record| record.unwrap()).map(|record| (record.tag, record.payload, record.latest)).eq(payloads));
+        let record = storage.history(0, &mut crc32).unwrap().next().unwrap().unwrap();
+        assert_eq!((record.payload, record.latest), (&[1][..], true));
+        assert_eq!(storage.history(0, &mut crc32).unwrap().count(), 1);
+
+        // Records before the committed ones in the same run are abandoned too
+        {
+            let mut tx = storage.begin(&mut desc_list, &mut crc32);
+            tx.update(0, &[3]).unwrap();
+        }
+        let mut tx = storage.begin(&mut desc_list, &mut crc32);
+        tx.update(0, &[4]).unwrap();
+        tx.commit().unwrap();
+        let payloads = [(0, &[1][..], false), (1, &[1][..], true), (0, &[4][..], true)];
+        assert!(storage.records(&desc_list, &mut crc32).unwrap().map(|record| record.unwrap()).map(|record| (record.tag, record.payload, record.latest)).eq(payloads));
+        let history = storage.history(0, &mut crc32).unwrap().map(|record| record.unwrap()).map(|record| (record.payload, record.latest));
+        assert!(history.eq([(&[4][..], true), (&[1][..], false)]));
+    }
+}
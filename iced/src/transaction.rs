@@ -0,0 +1,273 @@
+//! Atomic update of several records
+//!
+//! Transaction records are written with staged commit field and followed by
+//! transaction commit record. `init` applies staged records only once it has
+//! found the commit, so reset in the middle leaves every record as it was.
+
+use crate::{Storage, Mem, StorageMem, HeaderFormat, StorageHasher32, RecordDesc, RecordIndex, RecordState, InitStats, Error, Payload, ready, apply_record};
+use crate::{RECORD_STAGED, RECORD_TX_COMMIT};
+use crate::records::LogPos;
+
+/// Largest number of records updated by one transaction
+pub const MAX_TX_RECORDS : usize = 32;
+
+/// Transaction started by `Storage::begin`. Records are written as they are
+/// staged, but table is updated only on `commit`. Transaction dropped without
+/// commit leaves nothing.
+pub struct Transaction<'a, S, F, L : ?Sized, H> {
+    log    : &'a mut Storage<S, F>,
+    list   : &'a mut L,
+    hasher : &'a mut H,
+    // Tag and position of every staged record
+    staged : [(u32, usize); MAX_TX_RECORDS],
+    len    : usize,
+}
+
+impl<S : StorageMem, F : HeaderFormat> Storage<S, F> {
+
+    /// Start transaction updating records of `list`. Storage isn't compacted
+    /// while transaction is open, see `update_all_or_compact`.
+    pub fn begin<'a, L : RecordIndex + ?Sized, H : StorageHasher32<S::Word>>(&'a mut self, list : &'a mut L, hasher : &'a mut H) -> Transaction<'a, S, F, L, H> {
+        Transaction { log : self, list, hasher, staged : [(0, 0); MAX_TX_RECORDS], len : 0 }
+    }
+
+    /// Update records with given tags in `list` in one transaction,
+    /// reclaiming oldest pages while storage is full. Records which already
+    /// hold given payload are left out, like by `update`, only the rest
+    /// counts for `MAX_TX_RECORDS`.
+    pub fn update_all_or_compact(&mut self, list : &mut (impl RecordIndex + ?Sized), records : &[(u32, &[u8])], hasher : &mut impl StorageHasher32<S::Word>) -> Result<(),Error<S::Error>> {
+        ready(self.update_all_or_compact_async(list, records, hasher))
+    }
+}
+
+impl<S : StorageMem, F : HeaderFormat, L : RecordIndex + ?Sized, H : StorageHasher32<S::Word>> Transaction<'_, S, F, L, H> {
+
+    /// Stage new payload of record with given tag in table. Nothing is
+    /// written if transaction is already full.
+    pub fn update(&mut self, tag : u32, payload : &[u8]) -> Result<(),Error<S::Error>> {
+        if self.len == MAX_TX_RECORDS {
+            return Err(Error::TooManyTxRecords);
+        }
+        if self.list.position(tag).is_none() {
+            return Err(Error::UnknownTag);
+        }
+        self.staged[self.len] = (tag, ready(self.log.stage(tag, payload, self.hasher))?);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Write commit record and point table to staged records
+    pub fn commit(self) -> Result<(),Error<S::Error>> {
+        ready(self.log.commit_staged(self.list, &self.staged[.. self.len], self.hasher))
+    }
+}
+
+#[allow(private_bounds)]
+impl<S : Mem, F : HeaderFormat> Storage<S, F> {
+
+    /// Write record of transaction, returns its position
+    async fn stage(&mut self, tag : u32, payload : &[u8], hasher : &mut impl StorageHasher32<S::Word>) -> Result<usize,Error<S::Error>> {
+        let mut record = RecordDesc { tag, offset : None };
+        self.append(&mut record, Payload::Bytes(payload), RECORD_STAGED, hasher, false).await
+    }
+
+    async fn commit_staged(&mut self, list : &mut (impl RecordIndex + ?Sized), staged : &[(u32, usize)], hasher : &mut impl StorageHasher32<S::Word>) -> Result<(),Error<S::Error>> {
+        if staged.is_empty() {
+            return Ok(());
+        }
+        let mut commit = RecordDesc { tag : staged.len() as u32, offset : None };
+        self.append(&mut commit, Payload::Bytes(&[]), RECORD_TX_COMMIT, hasher, false).await?;
+        for &(tag, idx) in staged {
+            if let Some(record) = list.find_mut(tag) {
+                record.offset = Some(idx);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn update_all_or_compact_async(&mut self, list : &mut (impl RecordIndex + ?Sized), records : &[(u32, &[u8])], hasher : &mut impl StorageHasher32<S::Word>) -> Result<(),Error<S::Error>> {
+        if records.iter().any(|&(tag, _)| list.position(tag).is_none()) {
+            return Err(Error::UnknownTag);
+        }
+        self.check_initialized()?;
+        // Rewriting the same payload only wears flash, the rest is still
+        // written at once
+        let mut changed = [(0, &[][..]); MAX_TX_RECORDS];
+        let mut len = 0;
+        for &(tag, payload) in records {
+            let stored = match list.find(tag) {
+                Some(record) => self.is_stored(record, payload).await?,
+                None => false,
+            };
+            if stored {
+                self.skipped_writes += 1;
+            } else {
+                if len == MAX_TX_RECORDS {
+                    return Err(Error::TooManyTxRecords);
+                }
+                changed[len] = (tag, payload);
+                len += 1;
+            }
+        }
+        let changed = &changed[.. len];
+        self.write_or_compact(list, hasher, async |log, list, hasher| log.update_all(list, changed, hasher).await).await
+    }
+
+    async fn update_all(&mut self, list : &mut (impl RecordIndex + ?Sized), records : &[(u32, &[u8])], hasher : &mut impl StorageHasher32<S::Word>) -> Result<(),Error<S::Error>> {
+        let mut staged = [(0, 0); MAX_TX_RECORDS];
+        for (&(tag, payload), staged) in records.iter().zip(&mut staged) {
+            *staged = (tag, self.stage(tag, payload, hasher).await?);
+        }
+        self.commit_staged(list, &staged[.. records.len()], hasher).await
+    }
+
+    /// Apply records of committed transaction found by `init`: staged
+    /// records from `pos` up to commit at `end`, except the first `skip`
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn apply_staged(&self, list : &mut (impl RecordIndex + ?Sized), stats : &mut InitStats, on_unknown : &mut impl FnMut(RecordDesc), oldest : usize, mut pos : LogPos, mut skip : usize, end : LogPos, hasher : &mut impl StorageHasher32<S::Word>) -> Result<(),Error<S::Error>> {
+        while let Some((idx, state)) = self.next_valid(oldest, &mut pos, hasher).await? {
+            if (pos.0, idx) >= end {
+                break;
+            }
+            if let RecordState::Staged(tag, _) = state {
+                if skip > 0 {
+                    skip -= 1;
+                } else {
+                    apply_record(list, stats, on_unknown, RecordDesc { tag, offset : Some(idx) });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestMem;
+    use crate::tests::PowerLossMem;
+    use crc::crc32::{Digest, IEEE};
+    use crc::CalcType;
+
+    fn crc32_ethernet() -> Digest {
+        Digest::new_custom(IEEE, !0u32, 0u32, CalcType::Normal)
+    }
+
+    #[test]
+    fn transaction_test() {
+        let mut crc32 = crc32_ethernet();
+        let mut desc_list = [
+            RecordDesc {
+                tag : 0,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 1,
+                offset : None,
+            },
+            RecordDesc {
+                tag : 2,
+                offset : None,
+            },
+        ];
+
+        let mut storage = Storage::new(TestMem::new());
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        storage.update(&mut desc_list[0], &[1], &mut crc32).unwrap();
+        storage.update(&mut desc_list[1], &[1], &mut crc32).unwrap();
+
+        // Staged records are invisible until commit
+        {
+            let mut tx = storage.begin(&mut desc_list, &mut crc32);
+            tx.update(0, &[2]).unwrap();
+            tx.update(1, &[2]).unwrap();
+        }
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(storage.get(&desc_list[0]).unwrap().unwrap(), &[1]);
+        assert_eq!(storage.get(&desc_list[1]).unwrap().unwrap(), &[1]);
+
+        // Abandoned records don't count for the next transaction
+        let mut tx = storage.begin(&mut desc_list, &mut crc32);
+        tx.update(1, &[3]).unwrap();
+        tx.update(2, &[3]).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(storage.get(&desc_list[1]).unwrap().unwrap(), &[3]);
+        let stats = storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(stats.unique_tags, 3);
+        assert_eq!(storage.get(&desc_list[0]).unwrap().unwrap(), &[1]);
+        assert_eq!(storage.get(&desc_list[1]).unwrap().unwrap(), &[3]);
+        assert_eq!(storage.get(&desc_list[2]).unwrap().unwrap(), &[3]);
+
+        // Reset at any point leaves either every record old or every one new
+        let snapshot = storage.storage.clone();
+        let mut applied = false;
+        for ops_before_loss in 0 .. 0x40 {
+            let mut storage = Storage::new(PowerLossMem { mem : snapshot.clone(), ops_before_loss });
+            let mut list = desc_list;
+            storage.init(&mut list, &mut crc32).unwrap();
+            storage.update_all_or_compact(&mut list, &[(0, &[4]), (1, &[4]), (2, &[4])], &mut crc32).unwrap();
+
+            let mut storage = Storage::new(storage.storage.mem);
+            storage.init(&mut list, &mut crc32).unwrap();
+            let values = [0, 1, 2].map(|n| storage.get(&list[n]).unwrap().unwrap()[0]);
+            if values == [4, 4, 4] {
+                applied = true;
+            } else {
+                assert!(!applied);
+                assert_eq!(values, [1, 3, 3]);
+            }
+        }
+        assert!(applied);
+
+        // Committed records survive compaction, records of transactions
+        // spanning pages are applied
+        // Unchanged records are left out, the rest is still committed
+        let len = storage.len().unwrap();
+        storage.update_all_or_compact(&mut desc_list, &[(0, &[1]), (1, &[3])], &mut crc32).unwrap();
+        assert_eq!(storage.len().unwrap(), len);
+        storage.update_all_or_compact(&mut desc_list, &[(0, &[1]), (1, &[5])], &mut crc32).unwrap();
+        assert_eq!(storage.skipped_writes(), 3);
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(storage.get(&desc_list[1]).unwrap().unwrap(), &[5]);
+
+        for counter in 0 .. 60u8 {
+            storage.update_all_or_compact(&mut desc_list, &[(0, &[counter; 6]), (2, &[counter; 3])], &mut crc32).unwrap();
+        }
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(storage.get(&desc_list[0]).unwrap().unwrap(), &[59; 6]);
+        assert_eq!(storage.get(&desc_list[1]).unwrap().unwrap(), &[5]);
+        assert_eq!(storage.get(&desc_list[2]).unwrap().unwrap(), &[59; 3]);
+
+        // Tag missing in table and overfull transaction write nothing
+        let len = storage.len().unwrap();
+        match storage.update_all_or_compact(&mut desc_list, &[(0, &[1]), (3, &[1])], &mut crc32) {
+            Err(Error::UnknownTag) => {}
+            res => panic!("{:?}", res),
+        }
+        let records = [(1, &[6][..]); MAX_TX_RECORDS + 1];
+        match storage.update_all_or_compact(&mut desc_list, &records, &mut crc32) {
+            Err(Error::TooManyTxRecords) => {}
+            res => panic!("{:?}", res),
+        }
+        assert_eq!(storage.len().unwrap(), len);
+
+        let mut storage = Storage::new(TestMem::<0x800>::with_layout(crate::test_def::TEST_MIXED_SECTORS, !0));
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        let mut tx = storage.begin(&mut desc_list, &mut crc32);
+        match tx.update(3, &[1]) {
+            Err(Error::UnknownTag) => {}
+            res => panic!("{:?}", res),
+        }
+        for _ in 0 .. MAX_TX_RECORDS {
+            tx.update(1, &[6]).unwrap();
+        }
+        let len_full = tx.log.len().unwrap();
+        match tx.update(1, &[6]) {
+            Err(Error::TooManyTxRecords) => {}
+            res => panic!("{:?}", res),
+        }
+        assert_eq!(tx.log.len().unwrap(), len_full);
+        storage.init(&mut desc_list, &mut crc32).unwrap();
+        assert_eq!(storage.get(&desc_list[1]).unwrap(), None);
+    }
+}
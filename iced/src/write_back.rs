@@ -0,0 +1,88 @@
+//! Flush policy of write-back cached storage
+
+/// When cached changes are due to be written to storage memory, change is
+/// never flushed by policy without any limit set
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct FlushPolicy {
+    /// Flush once fields were set given number of times
+    pub max_changes : Option<usize>,
+    /// Flush once changes are given time old, time is in units of `now`
+    /// passed to `WriteBack`
+    pub max_age     : Option<u64>,
+}
+
+/// Changes of write-back cache since the last flush
+#[derive(Clone, Debug, Default)]
+pub struct WriteBack {
+    policy      : FlushPolicy,
+    changes     : usize,
+    // Time of the first change since the last flush
+    dirty_since : Option<u64>,
+}
+
+impl WriteBack {
+    pub fn new(policy : FlushPolicy) -> Self {
+        Self { policy, changes : 0, dirty_since : None }
+    }
+
+    pub fn set_policy(&mut self, policy : FlushPolicy) {
+        self.policy = policy;
+    }
+
+    /// Field was set at time `now`
+    pub fn changed(&mut self, now : u64) {
+        self.changes += 1;
+        self.dirty_since.get_or_insert(now);
+    }
+
+    /// Number of times fields were set since the last flush
+    pub fn changes(&self) -> usize {
+        self.changes
+    }
+
+    /// Changes should be flushed at time `now`, age of changes is counted
+    /// from the first one since the last flush
+    pub fn is_due(&self, now : u64) -> bool {
+        let since = match self.dirty_since {
+            Some(since) => since,
+            None => return false,
+        };
+        self.policy.max_changes.is_some_and(|max| self.changes >= max) ||
+            self.policy.max_age.is_some_and(|max| now.wrapping_sub(since) >= max)
+    }
+
+    /// Changes were written to storage memory
+    pub fn flushed(&mut self) {
+        self.changes = 0;
+        self.dirty_since = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_back_test() {
+        let mut write_back = WriteBack::new(FlushPolicy { max_changes : Some(3), max_age : Some(100) });
+        assert!(!write_back.is_due(0));
+
+        // Age is counted from the change, not from the first check
+        write_back.changed(1000);
+        write_back.changed(1050);
+        assert!(!write_back.is_due(1099));
+        assert!(write_back.is_due(1100));
+        write_back.flushed();
+        assert!(!write_back.is_due(1200));
+
+        for _ in 0 .. 3 {
+            write_back.changed(1200);
+        }
+        assert_eq!(write_back.changes(), 3);
+        assert!(write_back.is_due(1200));
+
+        // Nothing is due without limits
+        write_back.set_policy(FlushPolicy::default());
+        assert!(!write_back.is_due(u64::MAX));
+    }
+}
@@ -12,7 +12,7 @@ use crc::CalcType;
 
 use iced::generate_storage_ty;
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug, iced::FieldValue)]
 pub enum Mode {
     InAir,
     Lifting,
@@ -40,7 +40,7 @@ fn crc32_ethernet() -> impl StorageHasher32 {
 
 fn main() {
     
-    let mem = iced::TestMem([!0;0x100]);
+    let mem = iced::TestMem::new();
 
     let mut storage = PerMap::new(mem);
     let mut crc = crc32_ethernet();
@@ -8,7 +8,7 @@ use crc::CalcType;
 
 use iced::generate_storage_ty;
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug, iced::FieldValue)]
 pub enum Mode {
     InAir,
     Lifting,
@@ -30,6 +30,47 @@ generate_storage_ty! {
     }
 }
 
+// More fields than a transaction takes
+mod wide {
+    iced::generate_storage_ty! {
+        struct WideMap {
+            f0 : u8,
+            f1 : u8,
+            f2 : u8,
+            f3 : u8,
+            f4 : u8,
+            f5 : u8,
+            f6 : u8,
+            f7 : u8,
+            f8 : u8,
+            f9 : u8,
+            f10 : u8,
+            f11 : u8,
+            f12 : u8,
+            f13 : u8,
+            f14 : u8,
+            f15 : u8,
+            f16 : u8,
+            f17 : u8,
+            f18 : u8,
+            f19 : u8,
+            f20 : u8,
+            f21 : u8,
+            f22 : u8,
+            f23 : u8,
+            f24 : u8,
+            f25 : u8,
+            f26 : u8,
+            f27 : u8,
+            f28 : u8,
+            f29 : u8,
+            f30 : u8,
+            f31 : u8,
+            f32 : u8,
+        }
+    }
+}
+
 fn crc32_ethernet() -> impl StorageHasher32 {
     Digest::new_custom(IEEE, !0u32, 0u32, CalcType::Normal)
 }
@@ -37,7 +78,7 @@ fn crc32_ethernet() -> impl StorageHasher32 {
 #[test]
 fn test() {
     
-    let mem = iced::TestMem([!0;0x100]);
+    let mem = iced::TestMem::new();
 
     let mut storage = PerMap::new(mem);
     let mut crc = crc32_ethernet();
@@ -58,7 +99,197 @@ fn test() {
     storage.set_mode(Mode::Lifting, &mut crc).unwrap();
     storage.set_mode(Mode::InAir, &mut crc).unwrap();
 
-    let stats = storage.init(&mut crc);
+    assert_eq!(storage.get_cara().unwrap(), Some(42));
+    assert_eq!(storage.get_barray().unwrap(), Some([false; 5]));
+    // Setting the same value again writes nothing
+    assert_eq!(storage.skipped_writes(), 2);
+
+    let stats = storage.init(&mut crc).unwrap();
+    assert_eq!(stats.skipped_writes, 2);
     println!("Stats: {:#?}", stats);
     println!("{:?}", &storage);
 }
+
+#[test]
+fn compact_header_test() {
+    let mem = iced::TestMem::new();
+
+    let mut storage = PerMap::<_, iced::CompactHeader>::with_format(mem);
+    let mut crc = crc32_ethernet();
+    storage.init(&mut crc).unwrap();
+
+    storage.set_name(7u32, &mut crc).unwrap();
+    storage.set_flag(true, &mut crc).unwrap();
+    storage.set_barray([false, true, false, true, true], &mut crc).unwrap();
+
+    storage.init(&mut crc).unwrap();
+    assert_eq!(storage.get_name().unwrap(), Some(7));
+    assert_eq!(storage.get_flag().unwrap(), Some(true));
+    assert_eq!(storage.get_barray().unwrap(), Some([false, true, false, true, true]));
+    assert_eq!(storage.get_calib().unwrap(), None);
+}
+
+#[test]
+fn clear_test() {
+    let mem = iced::TestMem::new();
+
+    let mut storage = PerMap::new(mem);
+    let mut crc = crc32_ethernet();
+    storage.init(&mut crc).unwrap();
+
+    storage.set_calib(777u32, &mut crc).unwrap();
+    storage.set_calib2(5u16, &mut crc).unwrap();
+    storage.clear_calib(&mut crc).unwrap();
+    assert_eq!(storage.get_calib().unwrap(), None);
+
+    // Cleared field stays unset after reset, until it's set again
+    for sign in 0 .. 50u8 {
+        storage.set_sign(sign, &mut crc).unwrap();
+    }
+    storage.init(&mut crc).unwrap();
+    assert_eq!(storage.get_calib().unwrap(), None);
+    assert_eq!(storage.get_calib2().unwrap(), Some(5));
+    storage.set_calib(778u32, &mut crc).unwrap();
+    storage.init(&mut crc).unwrap();
+    assert_eq!(storage.get_calib().unwrap(), Some(778));
+}
+
+#[test]
+fn async_test() {
+    let mem = iced::YieldMem::new(iced::TestMem::new());
+
+    let mut storage = AsyncPerMap::new(mem);
+    let mut crc = crc32_ethernet();
+    iced::block_on(async {
+        storage.init(&mut crc).await.unwrap();
+
+        for name in 0 .. 40u32 {
+            storage.set_name(name, &mut crc).await.unwrap();
+        }
+        storage.set_mode(Mode::Landing, &mut crc).await.unwrap();
+        storage.set_barray([true, false, true, false, true], &mut crc).await.unwrap();
+
+        storage.init(&mut crc).await.unwrap();
+        assert_eq!(storage.get_name().await.unwrap(), Some(39));
+        assert!(matches!(storage.get_mode().await.unwrap(), Some(Mode::Landing)));
+        assert_eq!(storage.get_barray().await.unwrap(), Some([true, false, true, false, true]));
+        assert_eq!(storage.get_calib().await.unwrap(), None);
+    });
+}
+
+#[test]
+fn transaction_test() {
+    let mem = iced::TestMem::new();
+
+    let mut storage = PerMap::new(mem);
+    let mut crc = crc32_ethernet();
+    storage.init(&mut crc).unwrap();
+
+    for n in 0 .. 30u32 {
+        storage.transaction(&mut crc, |tx| {
+            tx.set_calib(n);
+            tx.set_calib2(n as u16 * 2);
+            tx.set_sign(n as u8 * 3);
+        }).unwrap();
+    }
+    storage.set_name(1u32, &mut crc).unwrap();
+
+    storage.init(&mut crc).unwrap();
+    assert_eq!(storage.get_calib().unwrap(), Some(29));
+    assert_eq!(storage.get_calib2().unwrap(), Some(58));
+    assert_eq!(storage.get_sign().unwrap(), Some(87));
+    assert_eq!(storage.get_name().unwrap(), Some(1));
+    assert_eq!(storage.get_num().unwrap(), None);
+}
+
+#[test]
+fn cached_test() {
+    let mem = iced::TestMem::new();
+
+    let policy = iced::FlushPolicy { max_changes : None, max_age : Some(1000) };
+    let mut storage = CachedPerMap::new(mem, policy);
+    let mut crc = crc32_ethernet();
+    storage.init(&mut crc).unwrap();
+
+    // Setters don't touch storage until flush
+    for name in 0 .. 10_000u32 {
+        storage.set_name(name, 500);
+    }
+    storage.set_mode(Mode::OnGround, 900);
+    assert_eq!(storage.get_name().unwrap(), Some(9999));
+    assert!(storage.dirty_fields().eq(["name", "mode"]));
+    storage.init(&mut crc).unwrap();
+    assert_eq!(storage.storage.get_name().unwrap(), None);
+
+    // Policy flushes changes once the first of them is old enough
+    assert!(!storage.poll_flush(500, &mut crc).unwrap());
+    assert!(!storage.poll_flush(1499, &mut crc).unwrap());
+    assert!(storage.poll_flush(1500, &mut crc).unwrap());
+    assert!(!storage.is_dirty());
+    assert!(!storage.poll_flush(5000, &mut crc).unwrap());
+
+    storage.init(&mut crc).unwrap();
+    assert_eq!(storage.get_name().unwrap(), Some(9999));
+    assert!(matches!(storage.get_mode().unwrap(), Some(Mode::OnGround)));
+
+    // Shutdown handler flushes whatever is left
+    storage.set_calib(5, 6000);
+    storage.flush(&mut crc).unwrap();
+    storage.init(&mut crc).unwrap();
+    assert_eq!(storage.storage.get_calib().unwrap(), Some(5));
+
+    // Field set back to the stored value isn't written again
+    storage.set_calib(5, 7000);
+    storage.set_name(9999, 7000);
+    storage.flush(&mut crc).unwrap();
+    assert_eq!(storage.storage.skipped_writes(), 2);
+}
+
+#[test]
+fn invalid_value_test() {
+    let mem = iced::TestMem::new();
+
+    let mut storage = PerMap::new(mem);
+    let mut crc = crc32_ethernet();
+    storage.init(&mut crc).unwrap();
+
+    // Bytes which aren't a valid field value aren't turned into one
+    storage.storage.update_or_compact(&mut storage.record_table, 6, &[2], &mut crc).unwrap();
+    storage.storage.update_or_compact(&mut storage.record_table, 7, &[0, 1, 0, 1, 7], &mut crc).unwrap();
+    storage.storage.update_or_compact(&mut storage.record_table, 8, &[0xff], &mut crc).unwrap();
+    assert!(matches!(storage.get_flag(), Err(iced::Error::CorruptedRecordOnGet)));
+    assert!(matches!(storage.get_barray(), Err(iced::Error::CorruptedRecordOnGet)));
+    assert!(matches!(storage.get_mode(), Err(iced::Error::CorruptedRecordOnGet)));
+
+    storage.set_mode(Mode::OnGround, &mut crc).unwrap();
+    assert!(matches!(storage.get_mode().unwrap(), Some(Mode::OnGround)));
+}
+
+#[test]
+fn wide_test() {
+    let mem = iced::TestMem::new();
+
+    let mut storage = wide::WideMap::new(mem);
+    let mut crc = crc32_ethernet();
+    storage.init(&mut crc).unwrap();
+
+    // Only transaction which changes too many fields fails, and writes nothing
+    storage.transaction(&mut crc, |tx| {
+        tx.set_f0(1);
+        tx.set_f31(1);
+        tx.set_f32(1);
+    }).unwrap();
+    let res = storage.transaction(&mut crc, |tx| {
+        tx.set_f0(2); tx.set_f1(2); tx.set_f2(2); tx.set_f3(2); tx.set_f4(2); tx.set_f5(2); tx.set_f6(2); tx.set_f7(2);
+        tx.set_f8(2); tx.set_f9(2); tx.set_f10(2); tx.set_f11(2); tx.set_f12(2); tx.set_f13(2); tx.set_f14(2); tx.set_f15(2);
+        tx.set_f16(2); tx.set_f17(2); tx.set_f18(2); tx.set_f19(2); tx.set_f20(2); tx.set_f21(2); tx.set_f22(2); tx.set_f23(2);
+        tx.set_f24(2); tx.set_f25(2); tx.set_f26(2); tx.set_f27(2); tx.set_f28(2); tx.set_f29(2); tx.set_f30(2); tx.set_f31(2);
+        tx.set_f32(2);
+    });
+    assert!(matches!(res, Err(iced::Error::TooManyTxRecords)));
+
+    storage.init(&mut crc).unwrap();
+    assert_eq!(storage.get_f0().unwrap(), Some(1));
+    assert_eq!(storage.get_f32().unwrap(), Some(1));
+    assert_eq!(storage.get_f1().unwrap(), None);
+}